@@ -21,3 +21,11 @@ pub mod email;
 #[cfg(feature = "bjig")]
 pub mod bjig;
 pub mod tcp;
+pub mod udp;
+pub mod syslog;
+pub mod modbus;
+pub mod downlink;
+pub mod tls;
+pub mod auth;
+pub mod framing;
+pub mod nmea;
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::config::CollectorCfg;
+
+use super::{Collector, CollectorFactory};
+use super::grpc::kraken::kraken_downlink_service_server::{KrakenDownlinkService, KrakenDownlinkServiceServer};
+use super::grpc::kraken::{DownlinkAck, DownlinkCommand};
+
+/// A command from the broker on its way to a device, answered through `ack`
+/// once the owning collector has written it out (or failed to).
+#[derive(Debug)]
+pub struct DownlinkMessage {
+    pub target: String,
+    pub content_type: String,
+    #[allow(dead_code)]
+    pub metadata: String,
+    pub payload: Vec<u8>,
+    pub ack: oneshot::Sender<Result<(), String>>,
+}
+
+impl DownlinkMessage {
+    pub fn acknowledge(self, result: Result<(), String>) {
+        // The requester may have timed out already; nothing to do then.
+        let _ = self.ack.send(result);
+    }
+}
+
+const DOWNLINK_QUEUE_SIZE: usize = 64;
+
+fn registry() -> &'static Mutex<HashMap<String, mpsc::Sender<DownlinkMessage>>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<String, mpsc::Sender<DownlinkMessage>>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Registers a collector as a downlink destination and returns the queue it must drain.
+pub fn register(collector_name: &str) -> mpsc::Receiver<DownlinkMessage> {
+    let (tx, rx) = mpsc::channel(DOWNLINK_QUEUE_SIZE);
+    registry().lock().unwrap().insert(collector_name.to_string(), tx);
+    debug!("{} collector registered for downlink", collector_name);
+    rx
+}
+
+async fn dispatch(command: DownlinkCommand, timeout: Duration) -> Result<(), String> {
+    let sender = registry().lock().unwrap().get(&command.collector_name).cloned()
+        .ok_or_else(|| format!("collector {} is not accepting downlink commands", command.collector_name))?;
+    let (ack_tx, ack_rx) = oneshot::channel();
+    let message = DownlinkMessage {
+        target: command.target,
+        content_type: command.content_type,
+        metadata: command.metadata,
+        payload: command.payload,
+        ack: ack_tx,
+    };
+    sender.try_send(message).map_err(|e| match e {
+        mpsc::error::TrySendError::Full(_) => format!("downlink queue of {} collector is full", command.collector_name),
+        mpsc::error::TrySendError::Closed(_) => format!("{} collector has stopped", command.collector_name),
+    })?;
+    match tokio::time::timeout(timeout, ack_rx).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(_)) => Err("downlink command was dropped before delivery".to_string()),
+        Err(_) => Err(format!("no delivery acknowledgement within {:?}", timeout)),
+    }
+}
+
+struct DownlinkServer {
+    ack_timeout: Duration,
+}
+
+#[tonic::async_trait]
+impl KrakenDownlinkService for DownlinkServer {
+    async fn send_downlink(&self, request: Request<DownlinkCommand>) -> Result<Response<DownlinkAck>, Status> {
+        let command = request.into_inner();
+        debug!("Downlink command for {} ({}): {} bytes", command.collector_name, command.target, command.payload.len());
+        let collector_name = command.collector_name.clone();
+        let ack = match dispatch(command, self.ack_timeout).await {
+            Ok(_) => DownlinkAck { delivered: true, message: String::new() },
+            Err(reason) => {
+                error!("Failed to deliver downlink command to {}: {}", collector_name, reason);
+                DownlinkAck { delivered: false, message: reason }
+            }
+        };
+        Ok(Response::new(ack))
+    }
+}
+
+pub struct Downlink {
+    config: CollectorCfg,
+}
+
+pub struct DownlinkFactory {
+    config: CollectorCfg,
+}
+
+impl DownlinkFactory {
+    pub fn new(config: CollectorCfg) -> Self {
+        Self { config }
+    }
+}
+
+impl CollectorFactory for DownlinkFactory {
+    fn create(&self) -> Box<dyn Collector> {
+        Box::new(Downlink { config: self.config.clone() })
+    }
+}
+
+impl Collector for Downlink {
+    fn name(&self) -> &'static str {
+        "downlink"
+    }
+
+    fn is_enable(&self) -> bool {
+        self.config.downlink.enable
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn start(&self) -> Result<(), anyhow::Error> {
+        let addr: SocketAddr = self.config.downlink.host.parse()?;
+        let server = DownlinkServer {
+            ack_timeout: Duration::from_secs(self.config.downlink.ack_timeout_sec),
+        };
+        info!("Downlink gRPC server listening on {}", addr);
+        Server::builder()
+            .add_service(KrakenDownlinkServiceServer::new(server))
+            .serve(addr)
+            .await?;
+        Ok(())
+    }
+}
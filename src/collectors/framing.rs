@@ -0,0 +1,379 @@
+use std::time::Duration;
+use crate::config::FramingCfg;
+
+const STX: u8 = 0x02;
+const ETX: u8 = 0x03;
+
+/// How a byte stream is cut into messages before forwarding.
+#[derive(Clone, Debug)]
+pub enum Framing {
+    /// Whatever a single read returned.
+    Raw,
+    /// Records terminated by a delimiter, which is not forwarded.
+    Delimiter(Vec<u8>),
+    /// Records preceded by their length (excluding the prefix itself).
+    LengthPrefix { width: usize, little_endian: bool },
+    /// Records of a fixed number of bytes.
+    Fixed(usize),
+    /// Records between STX (0x02) and ETX (0x03); bytes outside them are discarded.
+    StxEtx,
+    /// Records ended by a pause in the stream, detected by the caller through `flush`.
+    InterByteTimeout(Duration),
+}
+
+/// What to do with a frame larger than the maximum frame size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OversizedPolicy {
+    /// Discard the frame and continue with the next one.
+    Drop,
+    /// Report an error so the caller closes the connection.
+    Close,
+}
+
+/// Parses a byte string setting, accepting `\n`, `\r`, `\t`, `\0` and `\xHH` escapes.
+pub fn unescape(value: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let mut bytes = Vec::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut utf8 = [0u8; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&hex, 16)
+                    .map_err(|_| anyhow::anyhow!("Invalid escape \\x{} in {:?}", hex, value))?;
+                bytes.push(byte);
+            }
+            other => return Err(anyhow::anyhow!("Invalid escape \\{} in {:?}", other.unwrap_or(' '), value)),
+        }
+    }
+    Ok(bytes)
+}
+
+impl Framing {
+    pub fn from_config(config: &FramingCfg) -> Result<Self, anyhow::Error> {
+        match config.mode.as_str() {
+            "raw" => Ok(Framing::Raw),
+            "delimiter" => {
+                let delimiter = unescape(&config.delimiter)?;
+                if delimiter.is_empty() {
+                    return Err(anyhow::anyhow!("Delimiter must not be empty"));
+                }
+                Ok(Framing::Delimiter(delimiter))
+            }
+            "length_prefix" => {
+                let width = match config.length_prefix.as_str() {
+                    "u8" => 1,
+                    "u16" => 2,
+                    "u32" => 4,
+                    other => return Err(anyhow::anyhow!("Unknown length prefix {:?} (expected u8, u16 or u32)", other)),
+                };
+                let little_endian = match config.length_endian.as_str() {
+                    "big" => false,
+                    "little" => true,
+                    other => return Err(anyhow::anyhow!("Unknown byte order {:?} (expected big or little)", other)),
+                };
+                Ok(Framing::LengthPrefix { width, little_endian })
+            }
+            "fixed" if config.frame_size > 0 => Ok(Framing::Fixed(config.frame_size)),
+            "fixed" => Err(anyhow::anyhow!("Fixed framing requires a frame size greater than 0")),
+            "stx_etx" => Ok(Framing::StxEtx),
+            "timeout" if config.inter_byte_timeout_ms > 0 => {
+                Ok(Framing::InterByteTimeout(Duration::from_millis(config.inter_byte_timeout_ms)))
+            }
+            "timeout" => Err(anyhow::anyhow!("Timeout framing requires an inter-byte timeout greater than 0")),
+            other => Err(anyhow::anyhow!("Unknown framing mode {:?}", other)),
+        }
+    }
+}
+
+/// Accumulates received bytes and yields complete frames.
+pub struct FrameDecoder {
+    framing: Framing,
+    max_frame_size: usize,
+    policy: OversizedPolicy,
+    buf: Vec<u8>,
+    /// Bytes of an oversized length-prefixed frame still to be discarded.
+    skip: usize,
+    /// Set while discarding an oversized frame up to the next delimiter.
+    resync: bool,
+}
+
+impl FrameDecoder {
+    pub fn new(config: &FramingCfg) -> Result<Self, anyhow::Error> {
+        let policy = match config.oversized_policy.as_str() {
+            "drop" => OversizedPolicy::Drop,
+            "close" => OversizedPolicy::Close,
+            other => return Err(anyhow::anyhow!("Unknown oversized frame policy {:?} (expected drop or close)", other)),
+        };
+        Ok(Self {
+            framing: Framing::from_config(config)?,
+            max_frame_size: config.max_frame_size,
+            policy,
+            buf: Vec::new(),
+            skip: 0,
+            resync: false,
+        })
+    }
+
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Bytes of an incomplete frame, left over when the stream ends.
+    pub fn pending(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// The pause that ends a record, when the framing is `timeout`.
+    pub fn inter_byte_timeout(&self) -> Option<Duration> {
+        match self.framing {
+            Framing::InterByteTimeout(timeout) => Some(timeout),
+            _ => None,
+        }
+    }
+
+    /// Takes the buffered bytes as a frame, once the inter-byte timeout has passed.
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        Some(std::mem::take(&mut self.buf))
+    }
+
+    fn oversized(&self, size: usize) -> Result<(), anyhow::Error> {
+        match self.policy {
+            OversizedPolicy::Drop => {
+                warn!("Dropped frame of {} bytes (max_frame_size={})", size, self.max_frame_size);
+                Ok(())
+            }
+            OversizedPolicy::Close => Err(anyhow::anyhow!("Frame of {} bytes exceeds max_frame_size={}", size, self.max_frame_size)),
+        }
+    }
+
+    /// Returns the next complete frame, or an error when an oversized frame must close the stream.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        loop {
+            if self.skip > 0 {
+                let n = self.skip.min(self.buf.len());
+                self.buf.drain(..n);
+                self.skip -= n;
+                if self.skip > 0 {
+                    return Ok(None);
+                }
+            }
+            match &self.framing {
+                Framing::Raw => {
+                    if self.buf.is_empty() {
+                        return Ok(None);
+                    }
+                    return Ok(Some(std::mem::take(&mut self.buf)));
+                }
+                Framing::Delimiter(delimiter) => {
+                    let position = self.buf.windows(delimiter.len()).position(|window| window == delimiter.as_slice());
+                    let Some(position) = position else {
+                        if !self.resync && self.buf.len() > self.max_frame_size {
+                            self.oversized(self.buf.len())?;
+                            self.resync = true;
+                        }
+                        if self.resync {
+                            // Keep a possible partial delimiter at the end.
+                            let keep = (delimiter.len() - 1).min(self.buf.len());
+                            self.buf.drain(..self.buf.len() - keep);
+                        }
+                        return Ok(None);
+                    };
+                    let frame: Vec<u8> = self.buf.drain(..position).collect();
+                    self.buf.drain(..delimiter.len());
+                    if self.resync {
+                        self.resync = false;
+                        continue;
+                    }
+                    if frame.len() > self.max_frame_size {
+                        self.oversized(frame.len())?;
+                        continue;
+                    }
+                    return Ok(Some(frame));
+                }
+                Framing::LengthPrefix { width, little_endian } => {
+                    let (width, little_endian) = (*width, *little_endian);
+                    if self.buf.len() < width {
+                        return Ok(None);
+                    }
+                    let mut prefix = [0u8; 4];
+                    if little_endian {
+                        prefix[..width].copy_from_slice(&self.buf[..width]);
+                    } else {
+                        prefix[4 - width..].copy_from_slice(&self.buf[..width]);
+                    }
+                    let length = if little_endian { u32::from_le_bytes(prefix) } else { u32::from_be_bytes(prefix) } as usize;
+                    if length > self.max_frame_size {
+                        self.oversized(length)?;
+                        self.buf.drain(..width);
+                        self.skip = length;
+                        continue;
+                    }
+                    if self.buf.len() < width + length {
+                        return Ok(None);
+                    }
+                    self.buf.drain(..width);
+                    return Ok(Some(self.buf.drain(..length).collect()));
+                }
+                Framing::Fixed(size) => {
+                    let size = *size;
+                    if self.buf.len() < size {
+                        return Ok(None);
+                    }
+                    return Ok(Some(self.buf.drain(..size).collect()));
+                }
+                Framing::StxEtx => {
+                    if self.resync {
+                        // Discard the rest of an oversized frame up to its ETX.
+                        let Some(end) = self.buf.iter().position(|b| *b == ETX) else {
+                            self.buf.clear();
+                            return Ok(None);
+                        };
+                        self.buf.drain(..=end);
+                        self.resync = false;
+                    }
+                    let Some(start) = self.buf.iter().position(|b| *b == STX) else {
+                        self.buf.clear();
+                        return Ok(None);
+                    };
+                    self.buf.drain(..start);
+                    match self.buf[1..].iter().position(|b| *b == STX || *b == ETX).map(|i| i + 1) {
+                        // A new STX before the ETX: the previous frame was cut off, start over.
+                        Some(i) if self.buf[i] == STX => {
+                            warn!("Discarded {} bytes of an unterminated STX/ETX frame", i);
+                            self.buf.drain(..i);
+                            continue;
+                        }
+                        Some(end) => {
+                            let frame: Vec<u8> = self.buf[1..end].to_vec();
+                            self.buf.drain(..=end);
+                            if frame.len() > self.max_frame_size {
+                                self.oversized(frame.len())?;
+                                continue;
+                            }
+                            return Ok(Some(frame));
+                        }
+                        None => {
+                            if self.buf.len() - 1 > self.max_frame_size {
+                                self.oversized(self.buf.len() - 1)?;
+                                self.buf.clear();
+                                self.resync = true;
+                            }
+                            return Ok(None);
+                        }
+                    }
+                }
+                Framing::InterByteTimeout(_) => {
+                    if self.buf.len() > self.max_frame_size {
+                        self.oversized(self.buf.len())?;
+                        self.buf.clear();
+                    }
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(mode: &str) -> FramingCfg {
+        FramingCfg {
+            mode: mode.to_string(),
+            delimiter: "\\r\\n".to_string(),
+            length_prefix: "u16".to_string(),
+            length_endian: "big".to_string(),
+            frame_size: 3,
+            max_frame_size: 8,
+            oversized_policy: "drop".to_string(),
+            inter_byte_timeout_ms: 0,
+        }
+    }
+
+    fn frames(decoder: &mut FrameDecoder, data: &[u8]) -> Vec<Vec<u8>> {
+        decoder.extend(data);
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn unescape_escapes() {
+        assert_eq!(unescape("a\\r\\n\\x02\\0\\\\").unwrap(), b"a\r\n\x02\0\\");
+        assert!(unescape("\\xZZ").is_err());
+        assert!(unescape("\\q").is_err());
+    }
+
+    #[test]
+    fn delimiter_across_reads() {
+        let mut decoder = FrameDecoder::new(&config("delimiter")).unwrap();
+        assert_eq!(frames(&mut decoder, b"one\r"), Vec::<Vec<u8>>::new());
+        assert_eq!(frames(&mut decoder, b"\ntwo\r\nthr"), vec![b"one".to_vec(), b"two".to_vec()]);
+        assert_eq!(decoder.pending(), 3);
+    }
+
+    #[test]
+    fn delimiter_drops_oversized_frame() {
+        let mut decoder = FrameDecoder::new(&config("delimiter")).unwrap();
+        assert_eq!(frames(&mut decoder, b"0123456789\r\nok\r\n"), vec![b"ok".to_vec()]);
+    }
+
+    #[test]
+    fn delimiter_closes_on_oversized_frame() {
+        let mut config = config("delimiter");
+        config.oversized_policy = "close".to_string();
+        let mut decoder = FrameDecoder::new(&config).unwrap();
+        decoder.extend(b"0123456789");
+        assert!(decoder.next_frame().is_err());
+    }
+
+    #[test]
+    fn length_prefix_skips_oversized_frame() {
+        let mut decoder = FrameDecoder::new(&config("length_prefix")).unwrap();
+        assert_eq!(frames(&mut decoder, b"\x00\x0a0123"), Vec::<Vec<u8>>::new());
+        assert_eq!(frames(&mut decoder, b"456789\x00\x02ok"), vec![b"ok".to_vec()]);
+    }
+
+    #[test]
+    fn fixed_size_frames() {
+        let mut decoder = FrameDecoder::new(&config("fixed")).unwrap();
+        assert_eq!(frames(&mut decoder, b"abcdefg"), vec![b"abc".to_vec(), b"def".to_vec()]);
+        assert_eq!(decoder.pending(), 1);
+    }
+
+    #[test]
+    fn unknown_mode() {
+        assert!(FrameDecoder::new(&config("fixed_size")).is_err());
+    }
+
+    #[test]
+    fn stx_etx_skips_noise() {
+        let mut decoder = FrameDecoder::new(&config("stx_etx")).unwrap();
+        assert_eq!(frames(&mut decoder, b"noise\x02abc\x03\x02de"), vec![b"abc".to_vec()]);
+        assert_eq!(frames(&mut decoder, b"f\x03"), vec![b"def".to_vec()]);
+    }
+
+    #[test]
+    fn timeout_requires_inter_byte_timeout() {
+        let mut config = config("timeout");
+        assert!(FrameDecoder::new(&config).is_err());
+        config.inter_byte_timeout_ms = 50;
+        assert_eq!(FrameDecoder::new(&config).unwrap().inter_byte_timeout(), Some(Duration::from_millis(50)));
+    }
+}
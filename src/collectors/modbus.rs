@@ -0,0 +1,514 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use serialport::{ClearBuffer, SerialPort};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use super::Collector;
+use super::CollectorFactory;
+use super::grpc;
+use super::serial::LineSettings;
+use crate::config::{CollectorCfg, ModbusCfg};
+
+#[derive(Debug, serde::Serialize)]
+struct MetaData {
+    device: String,
+    unit_id: u8,
+    transport: String,
+    address: String,
+}
+
+/// The four Modbus data tables, keyed by their read function code.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Table {
+    Coil,
+    DiscreteInput,
+    Holding,
+    Input,
+}
+
+impl Table {
+    fn function_code(self) -> u8 {
+        match self {
+            Table::Coil => 0x01,
+            Table::DiscreteInput => 0x02,
+            Table::Holding => 0x03,
+            Table::Input => 0x04,
+        }
+    }
+
+    fn is_bit(self) -> bool {
+        matches!(self, Table::Coil | Table::DiscreteInput)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum DataType {
+    Bool,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+    U64,
+    I64,
+    F64,
+}
+
+impl DataType {
+    /// Number of 16-bit registers the value spans.
+    fn registers(self) -> u16 {
+        match self {
+            DataType::Bool | DataType::U16 | DataType::I16 => 1,
+            DataType::U32 | DataType::I32 | DataType::F32 => 2,
+            DataType::U64 | DataType::I64 | DataType::F64 => 4,
+        }
+    }
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+fn default_word_order() -> String {
+    "big".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+struct RegisterEntry {
+    name: String,
+    table: Table,
+    address: u16,
+    /// Defaults to bool for coils and discrete inputs, u16 for registers.
+    #[serde(default)]
+    data_type: Option<DataType>,
+    #[serde(default = "default_scale")]
+    scale: f64,
+    #[serde(default)]
+    offset: f64,
+    /// Order of the 16-bit words of 32/64-bit values: `big` (high word first) or `little`.
+    #[serde(default = "default_word_order")]
+    word_order: String,
+}
+
+impl RegisterEntry {
+    fn data_type(&self) -> DataType {
+        self.data_type.unwrap_or(if self.table.is_bit() { DataType::Bool } else { DataType::U16 })
+    }
+
+    /// Converts the registers read for this entry into a scaled JSON value.
+    fn decode(&self, words: &[u16]) -> Value {
+        let mut words = words.to_vec();
+        if self.word_order == "little" {
+            words.reverse();
+        }
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+        let raw = match self.data_type() {
+            DataType::Bool => return json!(words[0] != 0),
+            DataType::U16 => Value::from(words[0]),
+            DataType::I16 => Value::from(words[0] as i16),
+            DataType::U32 => Value::from(u32::from_be_bytes(bytes[..4].try_into().unwrap())),
+            DataType::I32 => Value::from(i32::from_be_bytes(bytes[..4].try_into().unwrap())),
+            DataType::F32 => Value::from(f32::from_be_bytes(bytes[..4].try_into().unwrap()) as f64),
+            DataType::U64 => Value::from(u64::from_be_bytes(bytes[..8].try_into().unwrap())),
+            DataType::I64 => Value::from(i64::from_be_bytes(bytes[..8].try_into().unwrap())),
+            DataType::F64 => Value::from(f64::from_be_bytes(bytes[..8].try_into().unwrap())),
+        };
+        // Integers stay integers unless scaling is configured.
+        if self.scale == 1.0 && self.offset == 0.0 {
+            return raw;
+        }
+        // NaN and infinity have no JSON number, so they stay null rather than becoming the offset.
+        match raw.as_f64() {
+            Some(value) => json!(value * self.scale + self.offset),
+            None => Value::Null,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceEntry {
+    name: String,
+    #[serde(default = "default_unit_id")]
+    unit_id: u8,
+    registers: Vec<RegisterEntry>,
+}
+
+fn default_unit_id() -> u8 {
+    1
+}
+
+#[derive(Debug, Deserialize)]
+struct RegisterMap {
+    devices: Vec<DeviceEntry>,
+}
+
+impl RegisterMap {
+    fn load(path: &str) -> Result<Self, anyhow::Error> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read Modbus register map at {}: {}", path, e))?;
+        let map: RegisterMap = serde_yaml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Failed to parse Modbus register map at {}: {}", path, e))?;
+        for device in &map.devices {
+            for entry in &device.registers {
+                if entry.table.is_bit() != (entry.data_type() == DataType::Bool) {
+                    return Err(anyhow::anyhow!(
+                        "Modbus register {}.{}: coils and discrete inputs are bool, registers are numbers",
+                        device.name, entry.name,
+                    ));
+                }
+                if entry.word_order != "big" && entry.word_order != "little" {
+                    return Err(anyhow::anyhow!(
+                        "Modbus register {}.{}: unknown word order {:?} (expected big or little)",
+                        device.name, entry.name, entry.word_order,
+                    ));
+                }
+            }
+        }
+        Ok(map)
+    }
+}
+
+/// CRC-16/MODBUS of an RTU frame, sent low byte first.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+fn exception_name(code: u8) -> &'static str {
+    match code {
+        0x01 => "illegal function",
+        0x02 => "illegal data address",
+        0x03 => "illegal data value",
+        0x04 => "server device failure",
+        0x05 => "acknowledge",
+        0x06 => "server device busy",
+        0x0A => "gateway path unavailable",
+        0x0B => "gateway target device failed to respond",
+        _ => "unknown exception",
+    }
+}
+
+enum Link {
+    Tcp(TcpStream),
+    Rtu(Box<dyn SerialPort>),
+}
+
+/// A Modbus master over TCP or RTU that reconnects on the next request after a transport error.
+struct Client {
+    config: ModbusCfg,
+    line_settings: LineSettings,
+    link: Option<Link>,
+    transaction_id: u16,
+}
+
+impl Client {
+    fn new(config: &ModbusCfg) -> Result<Self, anyhow::Error> {
+        if config.mode != "tcp" && config.mode != "rtu" {
+            return Err(anyhow::anyhow!("Unknown Modbus mode {:?} (expected tcp or rtu)", config.mode));
+        }
+        Ok(Self {
+            config: config.clone(),
+            line_settings: LineSettings::new(config.data_bits, &config.parity, config.stop_bits, "none")?,
+            link: None,
+            transaction_id: 0,
+        })
+    }
+
+    fn address(&self) -> &str {
+        if self.config.mode == "rtu" { &self.config.serial_port } else { &self.config.host }
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_millis(self.config.timeout_ms.max(1))
+    }
+
+    async fn connect(&self) -> Result<Link, anyhow::Error> {
+        if self.config.mode == "rtu" {
+            let port = serialport::new(&self.config.serial_port, self.config.baudrate)
+                .data_bits(self.line_settings.data_bits)
+                .parity(self.line_settings.parity)
+                .stop_bits(self.line_settings.stop_bits)
+                .timeout(self.timeout())
+                .open()?;
+            info!("Opened Modbus RTU port {} at {} baud", self.config.serial_port, self.config.baudrate);
+            return Ok(Link::Rtu(port));
+        }
+        let stream = tokio::time::timeout(self.timeout(), TcpStream::connect(&self.config.host)).await
+            .map_err(|_| anyhow::anyhow!("Timed out connecting to {}", self.config.host))??;
+        info!("Connected to Modbus TCP server {}", self.config.host);
+        Ok(Link::Tcp(stream))
+    }
+
+    async fn ensure_connected(&mut self) -> Result<(), anyhow::Error> {
+        if self.link.is_none() {
+            self.link = Some(self.connect().await?);
+        }
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.link.is_some()
+    }
+
+    /// Sends a request PDU and returns the response PDU, or an error for exceptions and transport failures.
+    async fn request(&mut self, unit_id: u8, pdu: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let timeout = self.timeout();
+        self.transaction_id = self.transaction_id.wrapping_add(1);
+        let transaction_id = self.transaction_id;
+        let Some(link) = self.link.as_mut() else {
+            return Err(anyhow::anyhow!("Not connected"));
+        };
+        let result = match link {
+            Link::Tcp(stream) => {
+                tokio::time::timeout(timeout, request_tcp(stream, transaction_id, unit_id, pdu)).await
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("No response within {:?}", timeout)))
+            }
+            Link::Rtu(port) => request_rtu(port, unit_id, pdu),
+        };
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                // On TCP a late or partial response would be taken for the next one, so start over.
+                // On RTU a silent unit or a corrupt frame says nothing about the port itself.
+                let port_ok = matches!(self.link, Some(Link::Rtu(_)))
+                    && e.downcast_ref::<io::Error>().is_none_or(|e| e.kind() == io::ErrorKind::TimedOut);
+                if !port_ok {
+                    self.link = None;
+                }
+                return Err(e);
+            }
+        };
+        match response.first() {
+            Some(function) if function & 0x80 != 0 => {
+                let code = response.get(1).copied().unwrap_or(0);
+                Err(anyhow::anyhow!("Modbus exception {} ({})", code, exception_name(code)))
+            }
+            Some(function) if *function == pdu[0] => Ok(response),
+            _ => {
+                self.link = None;
+                Err(anyhow::anyhow!("Unexpected Modbus response {:02x?}", response))
+            }
+        }
+    }
+
+    /// Reads one map entry and decodes it.
+    async fn read(&mut self, unit_id: u8, entry: &RegisterEntry) -> Result<Value, anyhow::Error> {
+        let quantity = entry.data_type().registers();
+        let mut pdu = vec![entry.table.function_code()];
+        pdu.extend_from_slice(&entry.address.to_be_bytes());
+        pdu.extend_from_slice(&quantity.to_be_bytes());
+        let response = self.request(unit_id, &pdu).await?;
+
+        let byte_count = *response.get(1).ok_or_else(|| anyhow::anyhow!("Empty Modbus response"))? as usize;
+        let data = response.get(2..2 + byte_count).ok_or_else(|| anyhow::anyhow!("Truncated Modbus response"))?;
+        let words: Vec<u16> = if entry.table.is_bit() {
+            vec![(data.first().copied().unwrap_or(0) & 1) as u16]
+        } else {
+            if data.len() < quantity as usize * 2 {
+                return Err(anyhow::anyhow!("Modbus response has {} bytes, expected {}", data.len(), quantity * 2));
+            }
+            data.chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect()
+        };
+        Ok(entry.decode(&words))
+    }
+}
+
+/// Modbus TCP: a 7-byte MBAP header (transaction, protocol 0, length, unit) in front of the PDU.
+async fn request_tcp(stream: &mut TcpStream, transaction_id: u16, unit_id: u8, pdu: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut frame = Vec::with_capacity(7 + pdu.len());
+    frame.extend_from_slice(&transaction_id.to_be_bytes());
+    frame.extend_from_slice(&0u16.to_be_bytes());
+    frame.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+    frame.push(unit_id);
+    frame.extend_from_slice(pdu);
+    stream.write_all(&frame).await?;
+
+    let mut header = [0u8; 7];
+    stream.read_exact(&mut header).await?;
+    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    if u16::from_be_bytes([header[0], header[1]]) != transaction_id || length < 2 {
+        return Err(anyhow::anyhow!("Invalid MBAP header {:02x?}", header));
+    }
+    let mut response = vec![0u8; length - 1];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+/// Modbus RTU: unit, PDU and CRC. The response length follows from the function code.
+fn request_rtu(port: &mut Box<dyn SerialPort>, unit_id: u8, pdu: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut frame = Vec::with_capacity(3 + pdu.len());
+    frame.push(unit_id);
+    frame.extend_from_slice(pdu);
+    frame.extend_from_slice(&crc16(&frame).to_le_bytes());
+    // Drop leftovers of an earlier response that timed out.
+    port.clear(ClearBuffer::Input)?;
+    port.write_all(&frame)?;
+    port.flush()?;
+
+    let mut response = vec![0u8; 3];
+    port.read_exact(&mut response)?;
+    // Exceptions are unit, function, code and CRC; normal responses carry a byte count.
+    let remaining = if response[1] & 0x80 != 0 { 2 } else { response[2] as usize + 2 };
+    let mut rest = vec![0u8; remaining];
+    port.read_exact(&mut rest)?;
+    response.extend_from_slice(&rest);
+
+    let (body, crc) = response.split_at(response.len() - 2);
+    if crc16(body).to_le_bytes() != crc {
+        return Err(anyhow::anyhow!("CRC mismatch in Modbus RTU response {:02x?}", response));
+    }
+    if body[0] != unit_id {
+        return Err(anyhow::anyhow!("Modbus RTU response from unit {}, expected {}", body[0], unit_id));
+    }
+    Ok(body[1..].to_vec())
+}
+
+pub struct Modbus {
+    config: CollectorCfg,
+}
+
+pub struct ModbusFactory {
+    config: CollectorCfg,
+}
+
+impl ModbusFactory {
+    pub fn new(config: CollectorCfg) -> Self {
+        Self { config }
+    }
+}
+
+impl CollectorFactory for ModbusFactory {
+    fn create(&self) -> Box<dyn Collector> {
+        Box::new(Modbus { config: self.config.clone() })
+    }
+}
+
+impl Collector for Modbus {
+    fn name(&self) -> &'static str {
+        "modbus"
+    }
+
+    fn is_enable(&self) -> bool {
+        self.config.modbus.enable
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn start(&self) -> Result<(), anyhow::Error> {
+        let modbus_config = &self.config.modbus;
+        let map = RegisterMap::load(&modbus_config.map_path)?;
+        let mut client = Client::new(modbus_config)?;
+        info!(
+            "Modbus collector polling {} device(s) over {} {} every {}s",
+            map.devices.len(), modbus_config.mode, client.address(), modbus_config.interval_sec,
+        );
+
+        let mut ticker = tokio::time::interval(Duration::from_secs(modbus_config.interval_sec.max(1)));
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            for device in &map.devices {
+                self.poll_device(&mut client, device).await;
+            }
+        }
+    }
+}
+
+impl Modbus {
+    /// Reads every entry of a device and forwards them as one JSON object; failed entries are null.
+    async fn poll_device(&self, client: &mut Client, device: &DeviceEntry) {
+        if let Err(e) = client.ensure_connected().await {
+            error!("Failed to connect to Modbus {} {}: {}", self.config.modbus.mode, client.address(), e);
+            return;
+        }
+        let mut values = Map::new();
+        let mut failures = 0;
+        for entry in &device.registers {
+            // After a transport error the rest of the device waits for the next poll.
+            if !client.is_connected() {
+                failures += 1;
+                values.insert(entry.name.clone(), Value::Null);
+                continue;
+            }
+            let value = match client.read(device.unit_id, entry).await {
+                Ok(value) => value,
+                Err(e) => {
+                    warn!("Failed to read Modbus register {}.{}: {}", device.name, entry.name, e);
+                    failures += 1;
+                    Value::Null
+                }
+            };
+            values.insert(entry.name.clone(), value);
+        }
+        if failures == device.registers.len() {
+            // Nothing to report, most likely the device or the link is down.
+            return;
+        }
+
+        let metadata = MetaData {
+            device: device.name.clone(),
+            unit_id: device.unit_id,
+            transport: self.config.modbus.mode.clone(),
+            address: client.address().to_string(),
+        };
+        let meta_json = json!(metadata);
+        let sent = grpc::send(
+            &self.config.grpc,
+            "modbus",
+            "application/json",
+            &serde_json::to_string(&meta_json).unwrap(),
+            &serde_json::to_vec(&values).unwrap(),
+        )
+        .await;
+        match sent {
+            Ok(_) => debug!("Sent {} Modbus values of {} to gRPC", values.len(), device.name),
+            Err(e) => error!("Failed to send to gRPC: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(yaml: &str) -> RegisterEntry {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn decode_scaled_register() {
+        let entry = entry("{name: t, table: holding, address: 0, data_type: i16, scale: 0.1, offset: -40}");
+        assert_eq!(entry.decode(&[0xFF9C]), json!(-50.0));
+    }
+
+    #[test]
+    fn decode_word_order() {
+        let big = entry("{name: t, table: input, address: 0, data_type: u32}");
+        let little = entry("{name: t, table: input, address: 0, data_type: u32, word_order: little}");
+        assert_eq!(big.decode(&[0x0001, 0x0002]), json!(0x0001_0002u32));
+        assert_eq!(little.decode(&[0x0002, 0x0001]), json!(0x0001_0002u32));
+    }
+
+    #[test]
+    fn decode_nan_is_null() {
+        let entry = entry("{name: t, table: holding, address: 0, data_type: f32, scale: 2, offset: 1}");
+        let nan = f32::NAN.to_bits();
+        assert_eq!(entry.decode(&[(nan >> 16) as u16, nan as u16]), Value::Null);
+    }
+
+    #[test]
+    fn crc16_of_read_request() {
+        // Read 1 holding register at 0 from unit 1: 01 03 00 00 00 01 84 0A
+        assert_eq!(crc16(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x01]).to_le_bytes(), [0x84, 0x0A]);
+    }
+}
@@ -1,8 +1,313 @@
-use rumqttd::{Broker, Config, Notification};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS, Transport};
+use rumqttd::protocol::{self, Publish, PublishProperties};
+use rumqttd::{Broker, Config, Meter, Notification};
+use notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_full::{new_debouncer, DebounceEventResult, Debouncer, RecommendedCache};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::mpsc;
 use super::Collector;
 use super::CollectorFactory;
 use super::grpc;
-use crate::config::CollectorCfg;
+use super::grpc::kraken::KrakenResponse;
+use super::downlink;
+use super::auth::constant_time_eq;
+use crate::config::{CollectorCfg, GrpcCfg, MqttClientCfg};
+
+#[derive(Debug, serde::Serialize)]
+struct MetaData {
+    topic: String,
+    // The broker link always receives at QoS 0 and rumqttd keeps the
+    // publisher's QoS private, so this is only known in client mode.
+    qos: Option<u8>,
+    retain: bool,
+    // rumqttd does not expose the publisher on a link, so this is taken
+    // from a "client_id" user property when the publisher provides one.
+    client_id: Option<String>,
+    user_properties: BTreeMap<String, String>,
+    response_topic: Option<String>,
+    // Set again on the reply, and forwarded so the broker can tell requests apart.
+    correlation_data: Option<String>,
+}
+
+impl MetaData {
+    fn new(topic: &[u8], qos: Option<u8>, retain: bool, user_properties: &[(String, String)]) -> Self {
+        let user_properties: BTreeMap<String, String> = user_properties.iter().cloned().collect();
+        Self {
+            topic: String::from_utf8_lossy(topic).to_string(),
+            qos,
+            retain,
+            client_id: user_properties.get("client_id").cloned(),
+            user_properties,
+            response_topic: None,
+            correlation_data: None,
+        }
+    }
+}
+
+/// Resolves where a broker response to a message on `topic` is published.
+/// The v5 response topic wins; otherwise `{topic}` in the pattern is replaced.
+fn reply_topic_for(topic: &str, response_topic: Option<&str>, pattern: &str) -> Option<String> {
+    if let Some(response_topic) = response_topic.filter(|s| !s.is_empty()) {
+        return Some(response_topic.to_string());
+    }
+    if pattern.is_empty() {
+        return None;
+    }
+    Some(pattern.replace("{topic}", topic))
+}
+
+const REPLY_TOPICS_CAPACITY: usize = 1024;
+const INBOUND_QUEUE_SIZE: usize = 64;
+
+/// Topics the collector has published broker responses to, so that its own replies
+/// are not forwarded again when a subscribed filter covers them. The oldest topics
+/// are forgotten once the capacity is reached.
+#[derive(Default)]
+struct ReplyTopics {
+    topics: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl ReplyTopics {
+    fn insert(&mut self, topic: &str) {
+        if !self.topics.insert(topic.to_string()) {
+            return;
+        }
+        self.order.push_back(topic.to_string());
+        if self.order.len() > REPLY_TOPICS_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.topics.remove(&oldest);
+            }
+        }
+    }
+
+    fn contains(&self, topic: &str) -> bool {
+        self.topics.contains(topic)
+    }
+}
+
+/// Picks the content type from the MQTT v5 property, or guesses it from the payload.
+fn content_type_of(payload: &[u8], content_type: Option<&str>, payload_format_indicator: Option<u8>) -> String {
+    if let Some(content_type) = content_type.filter(|s| !s.is_empty()) {
+        return content_type.to_string();
+    }
+    match std::str::from_utf8(payload) {
+        Ok(text) if serde_json::from_str::<serde_json::Value>(text).is_ok() => "application/json".to_string(),
+        Ok(_) => "text/plain".to_string(),
+        Err(_) if payload_format_indicator == Some(1) => "text/plain".to_string(),
+        Err(_) => "application/octet-stream".to_string(),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AclFile {
+    #[serde(default)]
+    clients: Vec<AclClient>,
+}
+
+// rumqttd only lets us accept or refuse a connection; it has no hook to check what a
+// connected client publishes or subscribes to, so topic rules are refused rather than
+// silently left unenforced.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AclClient {
+    username: String,
+    password: String,
+}
+
+/// Credentials of the embedded broker, reloaded when the file changes.
+#[derive(Clone)]
+struct Acl {
+    path: PathBuf,
+    clients: Arc<RwLock<Vec<AclClient>>>,
+}
+
+impl Acl {
+    fn load(path: &str) -> Result<Self, anyhow::Error> {
+        let path = PathBuf::from(path);
+        let clients = Self::read(&path)?;
+        info!("Loaded {} MQTT client(s) from {}", clients.len(), path.display());
+        Ok(Self { path, clients: Arc::new(RwLock::new(clients)) })
+    }
+
+    fn read(path: &Path) -> Result<Vec<AclClient>, anyhow::Error> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read MQTT ACL file {}: {}", path.display(), e))?;
+        let acl: AclFile = serde_yaml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Failed to parse MQTT ACL file {}: {}", path.display(), e))?;
+        Ok(acl.clients)
+    }
+
+    fn reload(&self) {
+        match Self::read(&self.path) {
+            Ok(clients) => {
+                info!("Reloaded {} MQTT client(s) from {}", clients.len(), self.path.display());
+                *self.clients.write().unwrap() = clients;
+            }
+            // Keep serving the previous credentials rather than locking everyone out.
+            Err(e) => error!("{}", e),
+        }
+    }
+
+    fn authenticate(&self, client_id: &str, username: &str, password: &str) -> bool {
+        let authenticated = self.clients.read().unwrap().iter()
+            .any(|client| client.username == username
+                && constant_time_eq(client.password.as_bytes(), password.as_bytes()));
+        if !authenticated {
+            warn!("MQTT authentication failed for client {} (username: {:?})", client_id, username);
+        }
+        authenticated
+    }
+
+    /// Watches the directory rather than the file so that editors replacing the file are noticed.
+    fn watch(&self) -> Result<Debouncer<RecommendedWatcher, RecommendedCache>, anyhow::Error> {
+        let acl = self.clone();
+        let file_name = self.path.file_name().map(|name| name.to_os_string());
+        let mut debouncer = new_debouncer(Duration::from_secs(1), None, move |result: DebounceEventResult| {
+            if let Ok(events) = result {
+                let changed = events.iter()
+                    .flat_map(|event| event.paths.iter())
+                    .any(|path| path.file_name().map(|name| name.to_os_string()) == file_name);
+                if changed {
+                    acl.reload();
+                }
+            }
+        })?;
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        debouncer.watch(&dir, RecursiveMode::NonRecursive)?;
+        Ok(debouncer)
+    }
+}
+
+/// Sends a message to the broker and returns its response when it is addressed back to MQTT.
+async fn forward_message(grpc_config: &GrpcCfg, metadata: &MetaData, content_type: &str, payload: &[u8]) -> Result<Option<KrakenResponse>, Box<dyn std::error::Error + Send + Sync>> {
+    let meta_json = json!(metadata);
+    let sent = grpc::send(
+        grpc_config,
+        "mqtt",
+        content_type,
+        &serde_json::to_string(&meta_json).unwrap(),
+        payload,
+    ).await;
+    match sent {
+        Ok(response) => {
+            debug!("Sent message to grpc server: {:?}", response);
+            let kraken_response = response.into_inner();
+            if kraken_response.collector_name == "mqtt" && !kraken_response.payload.is_empty() {
+                Ok(Some(kraken_response))
+            } else {
+                Ok(None)
+            }
+        }
+        Err(e) => {
+            error!("Failed to send to grpc: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Forwards messages received in client mode and publishes the broker responses to their reply topics.
+async fn forward_client_messages(mut inbound_rx: mpsc::Receiver<rumqttc::Publish>, client: AsyncClient, grpc_config: GrpcCfg, reply_topic_pattern: String, qos: QoS) {
+    let mut reply_topics = ReplyTopics::default();
+    while let Some(publish) = inbound_rx.recv().await {
+        if reply_topics.contains(&publish.topic) {
+            trace!("Skipping reply message on {}", publish.topic);
+            continue;
+        }
+        let metadata = MetaData::new(publish.topic.as_bytes(), Some(publish.qos as u8), publish.retain, &[]);
+        let content_type = content_type_of(&publish.payload, None, None);
+        let response = forward_message(&grpc_config, &metadata, &content_type, &publish.payload).await;
+        let reply_topic = reply_topic_for(&metadata.topic, None, &reply_topic_pattern);
+        if let (Ok(Some(response)), Some(reply_topic)) = (response, reply_topic) {
+            reply_topics.insert(&reply_topic);
+            match client.publish(&reply_topic, qos, false, response.payload).await {
+                Ok(_) => debug!("Published broker response to {}", reply_topic),
+                Err(e) => error!("Failed to publish broker response to {}: {}", reply_topic, e),
+            }
+        }
+    }
+}
+
+/// Health counters of the embedded broker, reported to the Kraken broker periodically.
+#[derive(Debug, Default)]
+struct BrokerStats {
+    connections: AtomicUsize,
+    subscriptions: AtomicUsize,
+    failed_publishes: AtomicUsize,
+    forwarded: AtomicU64,
+    forward_failures: AtomicU64,
+    restarts: AtomicU64,
+}
+
+impl BrokerStats {
+    fn update(&self, meters: &[Meter]) {
+        for meter in meters {
+            if let Meter::Router(_, router) = meter {
+                self.connections.store(router.total_connections, Ordering::Relaxed);
+                self.subscriptions.store(router.total_subscriptions, Ordering::Relaxed);
+                self.failed_publishes.store(router.failed_publishes, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn snapshot(&self) -> serde_json::Value {
+        json!({
+            "connected_clients": self.connections.load(Ordering::Relaxed),
+            "subscriptions": self.subscriptions.load(Ordering::Relaxed),
+            "broker_failed_publishes": self.failed_publishes.load(Ordering::Relaxed),
+            "forwarded": self.forwarded.load(Ordering::Relaxed),
+            "forward_failures": self.forward_failures.load(Ordering::Relaxed),
+            "restarts": self.restarts.load(Ordering::Relaxed),
+        })
+    }
+}
+
+fn qos_from(level: u8) -> QoS {
+    match level {
+        0 => QoS::AtMostOnce,
+        2 => QoS::ExactlyOnce,
+        _ => QoS::AtLeastOnce,
+    }
+}
+
+fn client_options(config: &MqttClientCfg) -> Result<MqttOptions, anyhow::Error> {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(config.keep_alive_sec));
+    // A persistent session lets the remote broker queue QoS 1/2 messages while we are offline.
+    options.set_clean_session(config.clean_session);
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.clone().unwrap_or_default());
+    }
+    if let Some(ca_path) = &config.tls_ca_path {
+        let ca = fs::read(ca_path)
+            .map_err(|e| anyhow::anyhow!("Failed to read MQTT CA certificate {}: {}", ca_path, e))?;
+        let client_auth = match (&config.tls_cert_path, &config.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let cert = fs::read(cert_path)
+                    .map_err(|e| anyhow::anyhow!("Failed to read MQTT client certificate {}: {}", cert_path, e))?;
+                let key = fs::read(key_path)
+                    .map_err(|e| anyhow::anyhow!("Failed to read MQTT client key {}: {}", key_path, e))?;
+                Some((cert, key))
+            }
+            _ => None,
+        };
+        options.set_transport(Transport::tls(ca, client_auth, None));
+    }
+    Ok(options)
+}
 
 pub struct Mqtt {
     config: CollectorCfg,
@@ -35,68 +340,274 @@ impl Collector for Mqtt {
 
     #[tokio::main(flavor = "current_thread")]
     async fn start(&self) -> Result<(), anyhow::Error> {
+        match self.config.mqtt.mode.as_str() {
+            "broker" => self.run_broker().await,
+            "client" => self.run_client().await,
+            mode => Err(anyhow::anyhow!("Unknown KRKNC_MQTT_MODE {:?}: expected broker or client", mode)),
+        }
+    }
+}
+
+impl Mqtt {
+    async fn run_client(&self) -> Result<(), anyhow::Error> {
+        let client_config = &self.config.mqtt.client;
+        let options = client_options(client_config)?;
+        let (client, mut eventloop) = AsyncClient::new(options, 100);
+        let qos = qos_from(client_config.qos);
+
+        // Downlink commands are published to the topic given as their target.
+        let mut downlink_rx = downlink::register("mqtt");
+        let downlink_client = client.clone();
+        tokio::spawn(async move {
+            while let Some(message) = downlink_rx.recv().await {
+                let result = downlink_client.publish(&message.target, qos, false, message.payload.clone()).await
+                    .map_err(|e| e.to_string());
+                match &result {
+                    Ok(_) => debug!("Published downlink to {}", message.target),
+                    Err(e) => error!("Failed to publish downlink to {}: {}", message.target, e),
+                }
+                message.acknowledge(result);
+            }
+        });
+
+        info!("MQTT client connecting to {}:{} as {}", client_config.host, client_config.port, client_config.client_id);
+
+        // Forwarding runs in its own task so that keep-alives and acknowledgements
+        // are still handled by the event loop while the Kraken broker is slow.
+        let (inbound_tx, inbound_rx) = mpsc::channel(INBOUND_QUEUE_SIZE);
+        tokio::spawn(forward_client_messages(
+            inbound_rx,
+            client.clone(),
+            self.config.grpc.clone(),
+            self.config.mqtt.reply_topic_pattern.clone(),
+            qos,
+        ));
+
+        let mut retry_delay = Duration::from_secs(1);
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(connack))) => {
+                    info!("MQTT client connected (session_present={})", connack.session_present);
+                    retry_delay = Duration::from_secs(1);
+                    // Subscribe on every connection: a broker may report a session that lost the
+                    // subscriptions, and a failed subscribe is simply retried on the next ConnAck.
+                    for filter in &self.config.mqtt.topics {
+                        match client.subscribe(filter, qos).await {
+                            Ok(_) => debug!("MQTT client subscribed to {}", filter),
+                            Err(e) => error!("MQTT client failed to subscribe to {}: {}", filter, e),
+                        }
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    debug!("Publish: {:?}", publish);
+                    // The forwarder may be waiting on `publish`, which needs this loop, so never block here.
+                    if let Err(e) = inbound_tx.try_send(publish) {
+                        warn!("Dropped MQTT message: forward queue is full ({})", e);
+                    }
+                }
+                Ok(event) => {
+                    trace!("{:?}", event);
+                }
+                Err(e) => {
+                    error!("MQTT client connection error: {}. Reconnecting in {:?}", e, retry_delay);
+                    tokio::time::sleep(retry_delay).await;
+                    retry_delay = (retry_delay * 2).min(Duration::from_secs(60));
+                }
+            }
+        }
+    }
+
+    async fn run_broker(&self) -> Result<(), anyhow::Error> {
         let config = config::Config::builder()
             .add_source(config::File::with_name(&self.config.mqtt.config_path.to_owned()))
             .build()
             .unwrap();
-        let config: Config = config.try_deserialize().unwrap();
-        let config_for_info = config.clone();
-        let mut broker = Broker::new(config);
-
-        let (mut tx, mut rx) = broker.link("kraken").unwrap();
-        
-        std::thread::spawn(move || {
-            if let Err(e) = broker.start() {
-                error!("Failed to start MQTT Broker: {}", e);
-            } else {
-                debug!("MQTT Broker was started.");
+        let mut config: Config = config.try_deserialize().unwrap();
+        let acl = match &self.config.mqtt.acl_file_path {
+            Some(path) => Some(Acl::load(path)?),
+            None => None,
+        };
+        let _acl_watcher = match &acl {
+            Some(acl) => {
+                let servers = config.v4.iter_mut().chain(config.v5.iter_mut()).chain(config.ws.iter_mut())
+                    .flat_map(|servers| servers.values_mut());
+                for server in servers {
+                    let acl = acl.clone();
+                    server.set_auth_handler(move |client_id: String, username: String, password: String| {
+                        let acl = acl.clone();
+                        async move { acl.authenticate(&client_id, &username, &password) }
+                    });
+                }
+                Some(acl.watch()?)
             }
-        });
-        
-        tx.subscribe(&self.config.mqtt.topic).unwrap();
+            None => None,
+        };
+        let stats = Arc::new(BrokerStats::default());
 
         // Log TCP MQTT v4 endpoint
-        if let Some(server) = config_for_info.v4.as_ref().and_then(|v4| v4.get("1")) {
+        if let Some(server) = config.v4.as_ref().and_then(|v4| v4.get("1")) {
             debug!("MQTT Broker was started that is listening on {} (TCP v4)", server.listen.to_string());
         }
 
         // Log TCP MQTT v5 endpoint
-        if let Some(server) = config_for_info.v5.as_ref().and_then(|v5| v5.get("1")) {
+        if let Some(server) = config.v5.as_ref().and_then(|v5| v5.get("1")) {
             debug!("MQTT Broker was started that is listening on {} (TCP v5)", server.listen.to_string());
         }
 
         // Log WebSocket endpoint
-        if let Some(ws_server) = config_for_info.ws.as_ref().and_then(|ws| ws.get("1")) {
+        if let Some(ws_server) = config.ws.as_ref().and_then(|ws| ws.get("1")) {
             debug!("MQTT Broker was started that is listening on {} (WebSocket)", ws_server.listen.to_string());
         }
 
+        let mut broker = Broker::new(config);
+        let (mut tx, mut rx) = broker.link("kraken")?;
+        let (mut downlink_tx, downlink_link_rx) = broker.link("kraken-downlink")?;
+        let meters = broker.meters()?;
+
+        // rumqttd cannot stop a broker, so only the listeners are supervised: `Broker::start`
+        // returns once all of them have stopped, and is then run again on the same router,
+        // which keeps the links and subscriptions. A failed router cannot be replaced.
+        let listener_stats = stats.clone();
+        let broker_thread = thread::spawn(move || {
+            let mut restart_delay = Duration::from_secs(1);
+            loop {
+                let started_at = Instant::now();
+                if let Err(e) = broker.start() {
+                    error!("Failed to start MQTT Broker: {}", e);
+                    return;
+                }
+                listener_stats.restarts.fetch_add(1, Ordering::Relaxed);
+                if started_at.elapsed() > Duration::from_secs(60) {
+                    restart_delay = Duration::from_secs(1);
+                }
+                warn!("All MQTT Broker listeners have stopped, restarting them in {:?}", restart_delay);
+                thread::sleep(restart_delay);
+                restart_delay = (restart_delay * 2).min(Duration::from_secs(60));
+            }
+        });
+        debug!("MQTT Broker was started.");
+
+        let meter_stats = stats.clone();
+        thread::spawn(move || {
+            while let Ok(meters) = meters.recv() {
+                meter_stats.update(&meters);
+            }
+        });
+
+        // The forwarding loop blocks on the broker link, so downlink commands
+        // are published from their own thread through a second link.
+        let mut downlink_rx = downlink::register("mqtt");
+        thread::spawn(move || {
+            // Held so the router keeps the link open.
+            let _link_rx = downlink_link_rx;
+            while let Some(message) = downlink_rx.blocking_recv() {
+                let result = downlink_tx.publish(message.target.clone(), message.payload.clone())
+                    .map(|_| ())
+                    .map_err(|e| format!("{:?}", e));
+                match &result {
+                    Ok(_) => debug!("Published downlink to {}", message.target),
+                    Err(e) => error!("Failed to publish downlink to {}: {}", message.target, e),
+                }
+                message.acknowledge(result);
+            }
+        });
+
+        for filter in &self.config.mqtt.topics {
+            tx.subscribe(filter)?;
+            debug!("MQTT collector subscribed to {}", filter);
+        }
+
+        let stats_interval = Duration::from_secs(self.config.mqtt.stats_interval_sec);
+        let mut last_report = Instant::now();
+        let mut reply_topics = ReplyTopics::default();
         loop {
-            if let Some(notification) = rx.recv().unwrap() {
-                match notification {
-                    Notification::Forward(forward) => {
-                        debug!("Forward: {:?}", forward);
-                        let message = String::from_utf8_lossy(&forward.publish.payload);
-                        let sent = grpc::send(
-                            &self.config.grpc,
-                            "mqtt",
-                            "application/json",
-                            "{}",
-                            message.as_bytes(),
-                        ).await;
-                        if let Err(e) = sent {
-                            error!("Failed to send to grpc: {:?}", e);
-                        } else {
-                            debug!("Sent message to grpc server: {:?}", sent);
-                        }
+            if broker_thread.is_finished() {
+                return Err(anyhow::anyhow!("MQTT Broker has stopped"));
+            }
+            if !stats_interval.is_zero() && last_report.elapsed() >= stats_interval {
+                last_report = Instant::now();
+                self.report_stats(&stats).await;
+            }
+            let notification = match rx.recv_deadline(Instant::now() + Duration::from_secs(1)) {
+                Ok(Some(notification)) => notification,
+                Ok(None) => continue,
+                // The link only closes when the router has stopped, which takes the whole broker with it.
+                Err(e) => return Err(anyhow::anyhow!("MQTT Broker router has stopped: {:?}", e)),
+            };
+            match notification {
+                Notification::Forward(forward) => {
+                    debug!("Forward: {:?}", forward);
+                    let properties = forward.properties.unwrap_or_default();
+                    let publish = forward.publish;
+                    let mut metadata = MetaData::new(
+                        &publish.topic,
+                        None,
+                        publish.retain,
+                        &properties.user_properties,
+                    );
+                    if reply_topics.contains(&metadata.topic) {
+                        trace!("Skipping reply message on {}", metadata.topic);
+                        continue;
                     }
-                    v => {
-                        trace!("{:?}", v);
+                    metadata.response_topic = properties.response_topic.clone();
+                    metadata.correlation_data = properties.correlation_data.as_ref()
+                        .map(|data| BASE64.encode(data));
+                    let content_type = content_type_of(
+                        &publish.payload,
+                        properties.content_type.as_deref(),
+                        properties.payload_format_indicator,
+                    );
+                    let response = match forward_message(&self.config.grpc, &metadata, &content_type, &publish.payload).await {
+                        Ok(response) => {
+                            stats.forwarded.fetch_add(1, Ordering::Relaxed);
+                            response
+                        }
+                        Err(_) => {
+                            stats.forward_failures.fetch_add(1, Ordering::Relaxed);
+                            None
+                        }
+                    };
+                    let reply_topic = reply_topic_for(
+                        &metadata.topic,
+                        metadata.response_topic.as_deref(),
+                        &self.config.mqtt.reply_topic_pattern,
+                    );
+                    if let (Some(response), Some(reply_topic)) = (response, reply_topic) {
+                        reply_topics.insert(&reply_topic);
+                        let published = match properties.correlation_data {
+                            Some(correlation_data) => {
+                                // `LinkTx::publish` cannot carry v5 properties, so the packet is built here.
+                                let properties = PublishProperties { correlation_data: Some(correlation_data), ..Default::default() };
+                                let publish = Publish::new(reply_topic.as_bytes().to_vec(), response.payload, false);
+                                tx.send(protocol::Packet::Publish(publish, Some(properties))).await
+                            }
+                            None => tx.publish(reply_topic.clone(), response.payload),
+                        };
+                        match published {
+                            Ok(_) => debug!("Published broker response to {}", reply_topic),
+                            Err(e) => error!("Failed to publish broker response to {}: {:?}", reply_topic, e),
+                        }
                     }
                 }
-            } else {
-                error!("MQTT Broker disconnected");
+                v => {
+                    trace!("{:?}", v);
+                }
             }
         }
     }
-}
 
+    async fn report_stats(&self, stats: &BrokerStats) {
+        let snapshot = stats.snapshot();
+        info!("MQTT Broker stats: {}", snapshot);
+        let sent = grpc::send(
+            &self.config.grpc,
+            "mqtt_stats",
+            "application/json",
+            r#"{"type": "broker_stats"}"#,
+            snapshot.to_string().as_bytes(),
+        ).await;
+        if let Err(e) = sent {
+            error!("Failed to send MQTT Broker stats to grpc: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use serde_json::{json, Map, Value};
+
+const KNOTS_TO_KMH: f64 = 1.852;
+
+/// Verifies `$<body>*<checksum>` and returns the body, or None for anything else.
+fn checked_body(line: &str) -> Option<&str> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let (body, checksum) = line.strip_prefix('$')?.rsplit_once('*')?;
+    let expected = u8::from_str_radix(checksum, 16).ok()?;
+    (body.bytes().fold(0u8, |acc, b| acc ^ b) == expected).then_some(body)
+}
+
+fn number(field: Option<&&str>) -> Option<f64> {
+    field.and_then(|field| field.parse::<f64>().ok())
+}
+
+/// `ddmm.mmmm` with its hemisphere into signed decimal degrees.
+fn coordinate(value: Option<&&str>, hemisphere: Option<&&str>) -> Option<f64> {
+    let value = number(value)?;
+    let degrees = (value / 100.0).trunc() + (value % 100.0) / 60.0;
+    match hemisphere.copied() {
+        Some("S") | Some("W") => Some(-degrees),
+        Some("N") | Some("E") => Some(degrees),
+        _ => None,
+    }
+}
+
+/// `hhmmss.ss` into `hh:mm:ss.ss`.
+fn time(field: Option<&&str>) -> Option<String> {
+    let field = field.filter(|field| field.len() >= 6 && field.is_ascii())?;
+    Some(format!("{}:{}:{}", &field[..2], &field[2..4], &field[4..]))
+}
+
+/// `ddmmyy` into `20yy-mm-dd`.
+fn date(field: Option<&&str>) -> Option<String> {
+    let field = field.filter(|field| field.len() == 6 && field.bytes().all(|b| b.is_ascii_digit()))?;
+    Some(format!("20{}-{}-{}", &field[4..6], &field[2..4], &field[..2]))
+}
+
+fn fix_quality_name(quality: u64) -> &'static str {
+    match quality {
+        0 => "invalid",
+        1 => "gps",
+        2 => "dgps",
+        3 => "pps",
+        4 => "rtk",
+        5 => "float_rtk",
+        6 => "estimated",
+        7 => "manual",
+        8 => "simulation",
+        _ => "unknown",
+    }
+}
+
+fn insert(record: &mut Map<String, Value>, key: &str, value: Option<impl Into<Value>>) {
+    if let Some(value) = value {
+        record.insert(key.to_string(), value.into());
+    }
+}
+
+/// Satellites of a GSV group that is still arriving, one sentence at a time.
+#[derive(Default)]
+struct GsvGroup {
+    in_view: Option<u64>,
+    satellites: Vec<Value>,
+}
+
+/// Turns NMEA 0183 sentences into JSON records, either one per sentence or, when throttled,
+/// one merged fix per interval.
+pub struct NmeaDecoder {
+    throttle: Option<Duration>,
+    last_fix: Option<Instant>,
+    fix: Map<String, Value>,
+    gsv: HashMap<String, GsvGroup>,
+    /// Satellites in view of the last complete GSV group of each talker.
+    satellites: HashMap<String, (u64, Vec<Value>)>,
+}
+
+impl NmeaDecoder {
+    pub fn new(throttle_sec: u64) -> Self {
+        Self {
+            throttle: (throttle_sec > 0).then(|| Duration::from_secs(throttle_sec)),
+            last_fix: None,
+            fix: Map::new(),
+            gsv: HashMap::new(),
+            satellites: HashMap::new(),
+        }
+    }
+
+    /// Decodes one line and returns the record to forward, if any.
+    pub fn push(&mut self, line: &[u8]) -> Option<Value> {
+        let line = String::from_utf8_lossy(line);
+        let Some(body) = checked_body(&line) else {
+            warn!("Dropped NMEA sentence with a missing or wrong checksum: {:?}", line.trim_end());
+            return None;
+        };
+        let fields: Vec<&str> = body.split(',').collect();
+        if fields[0].len() != 5 || !fields[0].is_ascii() {
+            return None;
+        }
+        let (talker, sentence) = fields[0].split_at(2);
+        let record = match sentence {
+            "GGA" => Self::gga(&fields),
+            "RMC" => Self::rmc(&fields),
+            "VTG" => Self::vtg(&fields),
+            "GSV" => self.gsv(talker, &fields)?,
+            _ => {
+                trace!("Ignored NMEA sentence {}", fields[0]);
+                return None;
+            }
+        };
+
+        let Some(throttle) = self.throttle else {
+            let mut record = record;
+            record.insert("type".to_string(), json!(sentence.to_lowercase()));
+            record.insert("talker".to_string(), json!(talker));
+            return Some(Value::Object(record));
+        };
+        // A position sentence without a position means the fix was lost; do not report the old one.
+        if (sentence == "GGA" || sentence == "RMC") && !record.contains_key("latitude") {
+            self.fix.remove("latitude");
+            self.fix.remove("longitude");
+        }
+        // Satellites are merged per talker below.
+        if sentence != "GSV" {
+            self.fix.extend(record);
+        }
+        // A fix is reported on a position sentence, at most once per interval.
+        if (sentence != "GGA" && sentence != "RMC") || !self.fix.contains_key("latitude") {
+            return None;
+        }
+        if self.last_fix.is_some_and(|last_fix| last_fix.elapsed() < throttle) {
+            return None;
+        }
+        self.last_fix = Some(Instant::now());
+        let mut fix = self.fix.clone();
+        fix.insert("type".to_string(), json!("fix"));
+        if !self.satellites.is_empty() {
+            fix.insert("satellites_in_view".to_string(), json!(self.satellites.values().map(|(in_view, _)| in_view).sum::<u64>()));
+            fix.insert("satellites".to_string(), json!(self.satellites.values().flat_map(|(_, list)| list.clone()).collect::<Vec<_>>()));
+        }
+        Some(Value::Object(fix))
+    }
+
+    /// Time, position, fix quality, satellites used, HDOP and altitude.
+    fn gga(fields: &[&str]) -> Map<String, Value> {
+        let mut record = Map::new();
+        insert(&mut record, "time", time(fields.get(1)));
+        insert(&mut record, "latitude", coordinate(fields.get(2), fields.get(3)));
+        insert(&mut record, "longitude", coordinate(fields.get(4), fields.get(5)));
+        if let Some(quality) = fields.get(6).and_then(|field| field.parse::<u64>().ok()) {
+            record.insert("fix_quality".to_string(), json!(quality));
+            record.insert("fix_quality_name".to_string(), json!(fix_quality_name(quality)));
+        }
+        insert(&mut record, "satellites_used", fields.get(7).and_then(|field| field.parse::<u64>().ok()));
+        insert(&mut record, "hdop", number(fields.get(8)));
+        insert(&mut record, "altitude_m", number(fields.get(9)));
+        insert(&mut record, "geoid_separation_m", number(fields.get(11)));
+        record
+    }
+
+    /// Time, date, validity, position, speed and course.
+    fn rmc(fields: &[&str]) -> Map<String, Value> {
+        let mut record = Map::new();
+        insert(&mut record, "time", time(fields.get(1)));
+        insert(&mut record, "valid", fields.get(2).map(|status| *status == "A"));
+        insert(&mut record, "latitude", coordinate(fields.get(3), fields.get(4)));
+        insert(&mut record, "longitude", coordinate(fields.get(5), fields.get(6)));
+        insert(&mut record, "speed_knots", number(fields.get(7)));
+        insert(&mut record, "speed_kmh", number(fields.get(7)).map(|knots| knots * KNOTS_TO_KMH));
+        insert(&mut record, "course_deg", number(fields.get(8)));
+        insert(&mut record, "date", date(fields.get(9)));
+        record
+    }
+
+    /// Course over ground and speed.
+    fn vtg(fields: &[&str]) -> Map<String, Value> {
+        let mut record = Map::new();
+        insert(&mut record, "course_deg", number(fields.get(1)));
+        insert(&mut record, "course_magnetic_deg", number(fields.get(3)));
+        insert(&mut record, "speed_knots", number(fields.get(5)));
+        insert(&mut record, "speed_kmh", number(fields.get(7)));
+        record
+    }
+
+    /// Collects the satellites of a GSV group and returns them with its last sentence.
+    fn gsv(&mut self, talker: &str, fields: &[&str]) -> Option<Map<String, Value>> {
+        let total = fields.get(1)?.parse::<u64>().ok()?;
+        let index = fields.get(2)?.parse::<u64>().ok()?;
+        if index == 1 {
+            self.gsv.remove(talker);
+        }
+        let group = self.gsv.entry(talker.to_string()).or_default();
+        group.in_view = fields.get(3).and_then(|field| field.parse::<u64>().ok());
+        // Four fields per satellite, possibly followed by a signal ID (NMEA 4.10).
+        for satellite in fields.get(4..).unwrap_or_default().chunks(4).filter(|satellite| satellite.len() == 4) {
+            let mut entry = Map::new();
+            insert(&mut entry, "talker", Some(talker));
+            insert(&mut entry, "prn", satellite[0].parse::<u64>().ok());
+            insert(&mut entry, "elevation_deg", number(satellite.get(1)));
+            insert(&mut entry, "azimuth_deg", number(satellite.get(2)));
+            insert(&mut entry, "snr_db", number(satellite.get(3)));
+            group.satellites.push(Value::Object(entry));
+        }
+        if index < total {
+            return None;
+        }
+        let group = self.gsv.remove(talker)?;
+        let in_view = group.in_view.unwrap_or(group.satellites.len() as u64);
+        self.satellites.insert(talker.to_string(), (in_view, group.satellites.clone()));
+
+        let mut record = Map::new();
+        record.insert("satellites_in_view".to_string(), json!(in_view));
+        record.insert("satellites".to_string(), Value::Array(group.satellites));
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Appends the checksum to a sentence body.
+    fn sentence(body: &str) -> String {
+        format!("${}*{:02X}\r\n", body, body.bytes().fold(0u8, |acc, b| acc ^ b))
+    }
+
+    #[test]
+    fn push_gga() {
+        let mut decoder = NmeaDecoder::new(0);
+        let line = sentence("GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,");
+        let record = decoder.push(line.as_bytes()).unwrap();
+        assert_eq!(record["type"], "gga");
+        assert_eq!(record["talker"], "GP");
+        assert_eq!(record["time"], "12:35:19");
+        assert!((record["latitude"].as_f64().unwrap() - 48.1173).abs() < 1e-4);
+        assert_eq!(record["fix_quality_name"], "gps");
+    }
+
+    #[test]
+    fn push_non_ascii_address_does_not_panic() {
+        let mut decoder = NmeaDecoder::new(0);
+        // An invalid byte becomes U+FFFD, which fills a 5-byte address field on its own.
+        let line = sentence("\u{FFFD}GA,123519");
+        assert_eq!(line.split(',').next().unwrap().len(), 6);
+        assert!(decoder.push(line.as_bytes()).is_none());
+        let line = sentence("G\u{FFFD}A,123519");
+        assert!(decoder.push(line.as_bytes()).is_none());
+    }
+
+    #[test]
+    fn push_wrong_checksum() {
+        let mut decoder = NmeaDecoder::new(0);
+        assert!(decoder.push(b"$GPGGA,123519*00\r\n").is_none());
+    }
+}
@@ -1,14 +1,166 @@
-use std::io::{self};
-use std::time::Duration;
+use std::fs;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+use serde::Deserialize;
 use serde_json::json;
+use serialport::{DataBits, FlowControl, Parity, SerialPort, SerialPortType, StopBits};
+use tokio::sync::mpsc;
 use super::Collector;
 use super::CollectorFactory;
 use super::grpc;
-use crate::config::CollectorCfg;
+use super::downlink::{self, DownlinkMessage};
+use super::framing::FrameDecoder;
+use super::nmea::NmeaDecoder;
+use crate::config::{CollectorCfg, FramingCfg, SerialCfg};
 
 #[derive(Debug, serde::Serialize)]
 struct MetaData {
     device_name: String,
+    /// Name of the poll command this record answers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PollCommandEntry {
+    name: String,
+    #[serde(default)]
+    command: Option<String>,
+    #[serde(default)]
+    command_hex: Option<String>,
+    interval_sec: u64,
+    #[serde(default)]
+    reply_timeout_ms: Option<u64>,
+}
+
+fn default_reply_timeout_ms() -> u64 {
+    1000
+}
+
+#[derive(Debug, Deserialize)]
+struct PollFile {
+    #[serde(default = "default_reply_timeout_ms")]
+    reply_timeout_ms: u64,
+    commands: Vec<PollCommandEntry>,
+}
+
+/// A command written to the device on a schedule, for instruments that only answer when queried.
+struct PollCommand {
+    name: String,
+    bytes: Vec<u8>,
+    interval: Duration,
+    reply_timeout: Duration,
+    next_due: Instant,
+}
+
+/// Parses `01 03 00 00` or `01030000` into bytes.
+fn parse_hex(value: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let digits: Vec<char> = value.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(anyhow::anyhow!("Odd number of hex digits in {:?}", value));
+    }
+    digits.chunks(2)
+        .map(|pair| {
+            let pair: String = pair.iter().collect();
+            u8::from_str_radix(&pair, 16).map_err(|_| anyhow::anyhow!("Invalid hex byte {:?} in {:?}", pair, value))
+        })
+        .collect()
+}
+
+fn load_poll_commands(path: &str) -> Result<Vec<PollCommand>, anyhow::Error> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read serial poll file at {}: {}", path, e))?;
+    let file: PollFile = serde_yaml::from_str(&content)
+        .map_err(|e| anyhow::anyhow!("Failed to parse serial poll file at {}: {}", path, e))?;
+    file.commands.into_iter()
+        .map(|entry| {
+            let bytes = match (&entry.command, &entry.command_hex) {
+                (Some(command), None) => command.as_bytes().to_vec(),
+                (None, Some(hex)) => parse_hex(hex)?,
+                _ => return Err(anyhow::anyhow!("Serial poll command {} needs exactly one of command or command_hex", entry.name)),
+            };
+            if entry.interval_sec == 0 {
+                return Err(anyhow::anyhow!("Serial poll command {} needs an interval_sec greater than 0", entry.name));
+            }
+            Ok(PollCommand {
+                name: entry.name,
+                bytes,
+                interval: Duration::from_secs(entry.interval_sec),
+                reply_timeout: Duration::from_millis(entry.reply_timeout_ms.unwrap_or(file.reply_timeout_ms)),
+                next_due: Instant::now(),
+            })
+        })
+        .collect()
+}
+
+/// Line settings, validated once so that a typo fails at startup instead of on every reopen.
+pub(crate) struct LineSettings {
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+}
+
+impl LineSettings {
+    fn from_config(config: &SerialCfg) -> Result<Self, anyhow::Error> {
+        Self::new(config.data_bits, &config.parity, config.stop_bits, &config.flow_control)
+    }
+
+    pub(crate) fn new(data_bits: u8, parity: &str, stop_bits: u8, flow_control: &str) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            data_bits: match data_bits {
+                5 => DataBits::Five,
+                6 => DataBits::Six,
+                7 => DataBits::Seven,
+                8 => DataBits::Eight,
+                other => return Err(anyhow::anyhow!("Unsupported data bits {} (expected 5 to 8)", other)),
+            },
+            parity: match parity {
+                "none" => Parity::None,
+                "odd" => Parity::Odd,
+                "even" => Parity::Even,
+                other => return Err(anyhow::anyhow!("Unknown parity {:?} (expected none, odd or even)", other)),
+            },
+            stop_bits: match stop_bits {
+                1 => StopBits::One,
+                2 => StopBits::Two,
+                other => return Err(anyhow::anyhow!("Unsupported stop bits {} (expected 1 or 2)", other)),
+            },
+            flow_control: match flow_control {
+                "none" => FlowControl::None,
+                "software" => FlowControl::Software,
+                "hardware" => FlowControl::Hardware,
+                other => return Err(anyhow::anyhow!("Unknown flow control {:?} (expected none, software or hardware)", other)),
+            },
+        })
+    }
+}
+
+/// The USB device the port is looked up by, from `KRKNC_SERIAL_USB_*`.
+struct UsbDevice {
+    vid: Option<u16>,
+    pid: Option<u16>,
+    serial_number: Option<String>,
+}
+
+impl UsbDevice {
+    fn from_config(config: &SerialCfg) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            vid: config.usb_vid.as_deref().map(|vid| parse_usb_id("KRKNC_SERIAL_USB_VID", vid)).transpose()?,
+            pid: config.usb_pid.as_deref().map(|pid| parse_usb_id("KRKNC_SERIAL_USB_PID", pid)).transpose()?,
+            serial_number: config.usb_serial_number.clone(),
+        })
+    }
+
+    fn is_configured(&self) -> bool {
+        self.vid.is_some() || self.pid.is_some() || self.serial_number.is_some()
+    }
+}
+
+/// Parses a USB vendor or product id such as `2341` or `0x2341`.
+fn parse_usb_id(name: &str, value: &str) -> Result<u16, anyhow::Error> {
+    u16::from_str_radix(value.trim_start_matches("0x"), 16)
+        .map_err(|_| anyhow::anyhow!("Invalid {} {:?} (expected a hexadecimal id such as 2341)", name, value))
 }
 
 pub struct Serial {
@@ -42,50 +194,309 @@ impl Collector for Serial {
 
     #[tokio::main(flavor = "current_thread")]
     async fn start(&self) -> Result<(), anyhow::Error> {
-        let port_name = self.config.serial.port.clone();
-        let baud_rate = self.config.serial.baudrate;
-        let timeout_sec = self.config.serial.timeout;
-
-        let port = serialport::new(&port_name, baud_rate)
-            .timeout(Duration::from_millis(timeout_sec))
-            .open();
-        debug!("Connecting to serial device on {} at {} baud:", &port_name, &baud_rate);
-
-        match port {
-            Ok(mut port) => {
-                let mut serial_buf: Vec<u8> = vec![0; 1024];
-                loop {
-                    match port.read(serial_buf.as_mut_slice()) {
-                        Ok(t) => {
-                            if t > 0 {
-                                let metadata = MetaData {
-                                    device_name: self.config.serial.device_name.clone(),
-                                };
-                                let meta_json = json!(metadata);
-                                let sent = grpc::send(
-                                    &self.config.grpc,
-                                    "serial",
-                                    "application/octet-stream",
-                                    &serde_json::to_string(&meta_json).unwrap(),
-                                    &serial_buf[..t],
-                                ).await;
-                                match sent {
-                                    Ok(msg) => debug!("Sent message to grpc server: {:?}", msg),
-                                    Err(msg) => error!("Failed to send to grpc: {:?}", msg),
+        let serial_config = &self.config.serial;
+        let line_settings = LineSettings::from_config(serial_config)?;
+        let usb_device = UsbDevice::from_config(serial_config)?;
+        FrameDecoder::new(&self.framing())?;
+        let mut commands = match &serial_config.poll_file_path {
+            Some(path) => load_poll_commands(path)?,
+            None => Vec::new(),
+        };
+        if !commands.is_empty() {
+            info!("Serial collector polling {} command(s)", commands.len());
+        }
+        let mut downlink_rx = downlink::register("serial");
+
+        let max_delay = Duration::from_secs(serial_config.reconnect_max_sec.max(1));
+        let mut retry_delay = Duration::from_secs(1);
+        loop {
+            match self.open(&line_settings, &usb_device) {
+                Ok((port, port_name)) => {
+                    retry_delay = Duration::from_secs(1);
+                    // Poll right away after (re)opening.
+                    for command in &mut commands {
+                        command.next_due = Instant::now();
+                    }
+                    let e = self.read_loop(port, &port_name, &mut downlink_rx, &mut commands).await;
+                    error!("Serial device {} failed: {}", port_name, e);
+                }
+                Err(e) => error!("Failed to open serial device: {}", e),
+            }
+            info!("Reopening serial device in {:?}", retry_delay);
+            // Downlink commands cannot wait for the device to come back.
+            let sleep = tokio::time::sleep(retry_delay);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    _ = &mut sleep => break,
+                    Some(message) = downlink_rx.recv() => {
+                        message.acknowledge(Err("serial device is not connected".to_string()));
+                    }
+                }
+            }
+            retry_delay = (retry_delay * 2).min(max_delay);
+        }
+    }
+
+}
+
+impl Serial {
+    /// Finds the port by USB VID/PID/serial number when configured, otherwise uses `KRKNC_SERIAL_PORT`.
+    fn resolve_port(&self, usb_device: &UsbDevice) -> Result<String, anyhow::Error> {
+        if !usb_device.is_configured() {
+            return Ok(self.config.serial.port.clone());
+        }
+        for port in serialport::available_ports()? {
+            let SerialPortType::UsbPort(info) = &port.port_type else {
+                continue;
+            };
+            if usb_device.vid.is_some_and(|vid| vid != info.vid)
+                || usb_device.pid.is_some_and(|pid| pid != info.pid)
+                || usb_device.serial_number.as_ref().is_some_and(|serial| info.serial_number.as_ref() != Some(serial))
+            {
+                continue;
+            }
+            debug!("Found USB serial device {:04x}:{:04x} on {}", info.vid, info.pid, port.port_name);
+            return Ok(port.port_name);
+        }
+        Err(anyhow::anyhow!(
+            "No USB serial device matches vid={:04x?} pid={:04x?} serial_number={:?}",
+            usb_device.vid, usb_device.pid, usb_device.serial_number,
+        ))
+    }
+
+    fn open(&self, line_settings: &LineSettings, usb_device: &UsbDevice) -> Result<(Box<dyn SerialPort>, String), anyhow::Error> {
+        let serial_config = &self.config.serial;
+        let port_name = self.resolve_port(usb_device)?;
+        debug!(
+            "Connecting to serial device on {} at {} baud (data_bits={}, parity={}, stop_bits={}, flow_control={}, framing={}):",
+            &port_name, serial_config.baudrate, serial_config.data_bits, serial_config.parity,
+            serial_config.stop_bits, serial_config.flow_control, self.framing().mode,
+        );
+        let port = serialport::new(&port_name, serial_config.baudrate)
+            .data_bits(line_settings.data_bits)
+            .parity(line_settings.parity)
+            .stop_bits(line_settings.stop_bits)
+            .flow_control(line_settings.flow_control)
+            .timeout(self.read_timeout())
+            .open()
+            .map_err(|e| anyhow::anyhow!("Failed to open \"{}\". Error: {}", port_name, e))?;
+        info!("Opened serial device {} on {}", serial_config.device_name, port_name);
+        Ok((port, port_name))
+    }
+
+    /// NMEA sentences are lines, so NMEA decoding without other framing splits at line breaks.
+    fn framing(&self) -> FramingCfg {
+        let mut framing = self.config.serial.framing.clone();
+        if self.config.serial.nmea && framing.mode == "raw" {
+            framing.mode = "delimiter".to_string();
+            framing.delimiter = "\\n".to_string();
+        }
+        framing
+    }
+
+    fn read_timeout(&self) -> Duration {
+        let mut read_timeout = Duration::from_secs(self.config.serial.timeout_sec.max(1));
+        // A pause can only be noticed if a read gives up within it.
+        if let Ok(decoder) = FrameDecoder::new(&self.framing()) {
+            if let Some(inter_byte_timeout) = decoder.inter_byte_timeout() {
+                read_timeout = read_timeout.min(inter_byte_timeout);
+            }
+        }
+        read_timeout
+    }
+
+    /// Reads and forwards records until the port fails, returning the error.
+    async fn read_loop(
+        &self,
+        mut port: Box<dyn SerialPort>,
+        port_name: &str,
+        downlink_rx: &mut mpsc::Receiver<DownlinkMessage>,
+        commands: &mut [PollCommand],
+    ) -> anyhow::Error {
+        let mut decoder = FrameDecoder::new(&self.framing()).unwrap();
+        let inter_byte_timeout = decoder.inter_byte_timeout();
+        let mut serial_buf: Vec<u8> = vec![0; 1024];
+        let mut last_byte = Instant::now();
+        let base_timeout = self.read_timeout();
+        let mut current_timeout = base_timeout;
+        // The command waiting for its reply and the reply deadline; one command is outstanding at a time.
+        let mut awaiting: Option<(usize, Instant)> = None;
+        let mut nmea = self.config.serial.nmea.then(|| NmeaDecoder::new(self.config.serial.nmea_throttle_sec));
+        loop {
+            // Downlink commands are written between reads; the read timeout bounds their latency.
+            while let Ok(message) = downlink_rx.try_recv() {
+                let device_name = &self.config.serial.device_name;
+                if !message.target.is_empty() && message.target != *device_name && message.target != port_name {
+                    let reason = format!("serial device {} is not connected", message.target);
+                    message.acknowledge(Err(reason));
+                    continue;
+                }
+                let result = port.write_all(&message.payload)
+                    .and_then(|_| port.flush())
+                    .map_err(|e| e.to_string());
+                match &result {
+                    Ok(_) => debug!("Wrote {} bytes downlink to {}", message.payload.len(), port_name),
+                    Err(e) => error!("Failed to write downlink to {}: {}", port_name, e),
+                }
+                message.acknowledge(result);
+            }
+
+            let now = Instant::now();
+            if let Some((index, deadline)) = awaiting {
+                if now >= deadline {
+                    let command = &commands[index];
+                    warn!("No reply to serial command {} within {:?}", command.name, command.reply_timeout);
+                    awaiting = None;
+                }
+            }
+            if awaiting.is_none() {
+                let due = commands.iter().enumerate()
+                    .filter(|(_, command)| command.next_due <= now)
+                    .min_by_key(|(_, command)| command.next_due)
+                    .map(|(index, _)| index);
+                if let Some(index) = due {
+                    let command = &mut commands[index];
+                    if let Err(e) = port.write_all(&command.bytes).and_then(|_| port.flush()) {
+                        return e.into();
+                    }
+                    trace!("Wrote serial command {} ({} bytes) to {}", command.name, command.bytes.len(), port_name);
+                    command.next_due += command.interval;
+                    if command.next_due < now {
+                        command.next_due = now + command.interval;
+                    }
+                    awaiting = Some((index, now + command.reply_timeout));
+                }
+            }
+
+            // Wake up in time for the reply deadline or the next command.
+            let wake_at = match awaiting {
+                Some((_, deadline)) => Some(deadline),
+                None => commands.iter().map(|command| command.next_due).min(),
+            };
+            let timeout = wake_at
+                .map(|wake_at| base_timeout.min(wake_at.saturating_duration_since(Instant::now())))
+                .unwrap_or(base_timeout)
+                .max(Duration::from_millis(1));
+            if timeout != current_timeout {
+                if let Err(e) = port.set_timeout(timeout) {
+                    return e.into();
+                }
+                current_timeout = timeout;
+            }
+
+            match port.read(serial_buf.as_mut_slice()) {
+                Ok(t) => {
+                    if t > 0 {
+                        last_byte = Instant::now();
+                        decoder.extend(&serial_buf[..t]);
+                        loop {
+                            match decoder.next_frame() {
+                                Ok(Some(frame)) => {
+                                    let command = awaiting.take().map(|(index, _)| commands[index].name.clone());
+                                    if let Err(e) = self.handle_frame(&mut port, port_name, &frame, command, &mut nmea).await {
+                                        return e.into();
+                                    }
+                                }
+                                Ok(None) => break,
+                                Err(e) => {
+                                    // There is no connection to close; discard what was buffered instead.
+                                    error!("{} on {}, discarding buffered data", e, port_name);
+                                    decoder = FrameDecoder::new(&self.framing()).unwrap();
+                                    break;
                                 }
                             }
                         }
-                        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
-                        Err(e) => eprintln!("{:?}", e),
                     }
                 }
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
+                // Unplugging the device ends up here; the caller reopens it.
+                Err(e) => return e.into(),
+            }
+            if let Some(inter_byte_timeout) = inter_byte_timeout {
+                if last_byte.elapsed() >= inter_byte_timeout {
+                    if let Some(frame) = decoder.flush() {
+                        let command = awaiting.take().map(|(index, _)| commands[index].name.clone());
+                        if let Err(e) = self.handle_frame(&mut port, port_name, &frame, command, &mut nmea).await {
+                            return e.into();
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Forwards a record as is, or the JSON decoded from it when NMEA decoding is enabled.
+    async fn handle_frame(
+        &self,
+        port: &mut Box<dyn SerialPort>,
+        port_name: &str,
+        frame: &[u8],
+        command: Option<String>,
+        nmea: &mut Option<NmeaDecoder>,
+    ) -> io::Result<()> {
+        let Some(nmea) = nmea else {
+            return self.forward(port, port_name, "application/octet-stream", frame, command).await;
+        };
+        match nmea.push(frame) {
+            Some(record) => {
+                let payload = serde_json::to_vec(&record).unwrap();
+                self.forward(port, port_name, "application/json", &payload, command).await
             }
-            Err(e) => {
-                error!("Failed to open \"{}\". Error: {}", &port_name, e);
-                //::std::process::exit(1);
+            None => Ok(()),
+        }
+    }
+
+    /// Forwards a record and writes the broker response back to the port when it asks for it.
+    async fn forward(
+        &self,
+        port: &mut Box<dyn SerialPort>,
+        port_name: &str,
+        content_type: &str,
+        frame: &[u8],
+        command: Option<String>,
+    ) -> io::Result<()> {
+        let metadata = MetaData {
+            device_name: self.config.serial.device_name.clone(),
+            command,
+        };
+        let meta_json = json!(metadata);
+        let sent = grpc::send(
+            &self.config.grpc,
+            "serial",
+            content_type,
+            &serde_json::to_string(&meta_json).unwrap(),
+            frame,
+        ).await;
+        match sent {
+            Ok(msg) => {
+                debug!("Sent message to grpc server: {:?}", msg);
+                let kraken_response = msg.into_inner();
+                // response_type=serial のとき、payloadをシリアルポートに書き込む
+                if !kraken_response.payload.is_empty() {
+                    if let Ok(response_meta) = serde_json::from_str::<serde_json::Value>(&kraken_response.metadata) {
+                        if response_meta.get("response_type").and_then(|v| v.as_str()) == Some("serial") {
+                            port.write_all(&kraken_response.payload).and_then(|_| port.flush())?;
+                            debug!("Wrote {} bytes response to {}", kraken_response.payload.len(), port_name);
+                        }
+                    }
+                }
             }
+            Err(msg) => error!("Failed to send to grpc: {:?}", msg),
         }
         Ok(())
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-}
\ No newline at end of file
+    #[test]
+    fn usb_ids() {
+        assert_eq!(parse_usb_id("KRKNC_SERIAL_USB_VID", "2341").unwrap(), 0x2341);
+        assert_eq!(parse_usb_id("KRKNC_SERIAL_USB_VID", "0x10c4").unwrap(), 0x10c4);
+        assert!(parse_usb_id("KRKNC_SERIAL_USB_VID", "arduino").is_err());
+        assert!(parse_usb_id("KRKNC_SERIAL_USB_PID", "12345").is_err());
+    }
+}
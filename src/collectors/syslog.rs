@@ -0,0 +1,419 @@
+use std::net::SocketAddr;
+use serde_json::{json, Map, Value};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use super::Collector;
+use super::CollectorFactory;
+use super::grpc;
+use crate::config::{CollectorCfg, GrpcCfg, SyslogCfg};
+
+const FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news",
+    "uucp", "cron", "authpriv", "ftp", "ntp", "audit", "alert", "clock",
+    "local0", "local1", "local2", "local3", "local4", "local5", "local6", "local7",
+];
+const SEVERITIES: [&str; 8] = ["emerg", "alert", "crit", "err", "warning", "notice", "info", "debug"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// RFC 3164 4.3.3: a message without PRI is treated as user.notice.
+const DEFAULT_PRI: u8 = 13;
+
+#[derive(Debug, serde::Serialize)]
+struct MetaData {
+    source_addr: String,
+    transport: &'static str,
+}
+
+/// A parsed syslog message, forwarded to the broker as JSON.
+#[derive(Debug, serde::Serialize)]
+struct SyslogMessage {
+    format: &'static str,
+    facility: u8,
+    facility_name: &'static str,
+    severity: u8,
+    severity_name: &'static str,
+    timestamp: Option<String>,
+    hostname: Option<String>,
+    app_name: Option<String>,
+    procid: Option<String>,
+    msgid: Option<String>,
+    structured_data: Map<String, Value>,
+    message: String,
+}
+
+impl SyslogMessage {
+    fn new(format: &'static str, pri: u8) -> Self {
+        Self {
+            format,
+            facility: pri >> 3,
+            facility_name: FACILITIES[(pri >> 3) as usize],
+            severity: pri & 7,
+            severity_name: SEVERITIES[(pri & 7) as usize],
+            timestamp: None,
+            hostname: None,
+            app_name: None,
+            procid: None,
+            msgid: None,
+            structured_data: Map::new(),
+            message: String::new(),
+        }
+    }
+}
+
+/// Parses an RFC 5424 message, falling back to RFC 3164 for anything else.
+fn parse(raw: &[u8]) -> SyslogMessage {
+    let text = String::from_utf8_lossy(raw);
+    let text = text.trim_end_matches(['\r', '\n', '\0']);
+    let (pri, rest) = parse_pri(text).unwrap_or((DEFAULT_PRI, text));
+    if let Some(rfc5424) = rest.strip_prefix("1 ").and_then(|rest| parse_rfc5424(pri, rest)) {
+        return rfc5424;
+    }
+    parse_rfc3164(pri, rest)
+}
+
+fn parse_pri(text: &str) -> Option<(u8, &str)> {
+    let rest = text.strip_prefix('<')?;
+    let end = rest.find('>').filter(|end| (1..=3).contains(end))?;
+    let pri = rest[..end].parse::<u8>().ok().filter(|pri| *pri < 192)?;
+    Some((pri, &rest[end + 1..]))
+}
+
+/// Splits off the next space-separated field; the RFC 5424 NILVALUE `-` becomes None.
+fn next_field(text: &str) -> Option<(Option<String>, &str)> {
+    let (field, rest) = text.split_once(' ')?;
+    if field.is_empty() {
+        return None;
+    }
+    Some(((field != "-").then(|| field.to_string()), rest))
+}
+
+/// `TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA [MSG]`, after `<PRI>1 `.
+fn parse_rfc5424(pri: u8, text: &str) -> Option<SyslogMessage> {
+    let mut message = SyslogMessage::new("rfc5424", pri);
+    let (timestamp, rest) = next_field(text)?;
+    let (hostname, rest) = next_field(rest)?;
+    let (app_name, rest) = next_field(rest)?;
+    let (procid, rest) = next_field(rest)?;
+    let (msgid, rest) = next_field(rest)?;
+    message.timestamp = timestamp;
+    message.hostname = hostname;
+    message.app_name = app_name;
+    message.procid = procid;
+    message.msgid = msgid;
+
+    let rest = match rest.strip_prefix('-') {
+        Some(rest) => rest,
+        None => parse_structured_data(rest, &mut message.structured_data)?,
+    };
+    match rest.strip_prefix(' ') {
+        Some(msg) => message.message = msg.trim_start_matches('\u{feff}').to_string(),
+        None if rest.is_empty() => {}
+        None => return None,
+    }
+    Some(message)
+}
+
+/// Parses `[id name="value" ...][id ...]` into `{"id": {"name": "value"}}`, returning the rest.
+fn parse_structured_data<'a>(mut text: &'a str, elements: &mut Map<String, Value>) -> Option<&'a str> {
+    if !text.starts_with('[') {
+        return None;
+    }
+    while let Some(rest) = text.strip_prefix('[') {
+        let end = rest.find([' ', ']'])?;
+        let id = &rest[..end];
+        let mut params = Map::new();
+        text = &rest[end..];
+        loop {
+            if let Some(rest) = text.strip_prefix(']') {
+                text = rest;
+                break;
+            }
+            let rest = text.strip_prefix(' ')?;
+            let (name, rest) = rest.split_once("=\"")?;
+            let mut value = String::new();
+            let mut chars = rest.char_indices();
+            let mut end = None;
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        end = Some(i + 1);
+                        break;
+                    }
+                    // Only `"`, `\` and `]` are escaped; other backslashes are kept.
+                    '\\' => match chars.next() {
+                        Some((_, escaped @ ('"' | '\\' | ']'))) => value.push(escaped),
+                        Some((_, other)) => {
+                            value.push('\\');
+                            value.push(other);
+                        }
+                        None => return None,
+                    },
+                    c => value.push(c),
+                }
+            }
+            params.insert(name.to_string(), Value::String(value));
+            text = &rest[end?..];
+        }
+        elements.insert(id.to_string(), Value::Object(params));
+    }
+    Some(text)
+}
+
+/// `TIMESTAMP HOSTNAME TAG[PID]: MSG`, where every part is optional in practice.
+fn parse_rfc3164(pri: u8, text: &str) -> SyslogMessage {
+    let mut message = SyslogMessage::new("rfc3164", pri);
+    let mut rest = text;
+
+    // `Mmm dd hh:mm:ss`, or an RFC 3339 timestamp as sent by many current devices.
+    let bytes = rest.as_bytes();
+    if bytes.len() > 15 && bytes[15] == b' ' && bytes[..15].is_ascii() && MONTHS.contains(&&rest[..3]) && bytes[9] == b':' && bytes[12] == b':' {
+        message.timestamp = Some(rest[..15].to_string());
+        rest = &rest[16..];
+    } else if let Some((timestamp, after)) = rest.split_once(' ') {
+        if chrono::DateTime::parse_from_rfc3339(timestamp).is_ok() {
+            message.timestamp = Some(timestamp.to_string());
+            rest = after;
+        }
+    }
+
+    // The hostname only follows a timestamp, and some senders omit it before the tag.
+    if message.timestamp.is_some() {
+        if let Some((hostname, after)) = rest.split_once(' ') {
+            if !hostname.is_empty() && !hostname.ends_with(':') && !hostname.contains('[') {
+                message.hostname = Some(hostname.to_string());
+                rest = after;
+            }
+        }
+    }
+
+    let tag_end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/')));
+    if let Some(tag_end) = tag_end.filter(|end| *end > 0) {
+        let after_tag = &rest[tag_end..];
+        let (procid, after_tag) = match after_tag.strip_prefix('[').and_then(|s| s.split_once(']')) {
+            Some((procid, after)) => (Some(procid.to_string()), after),
+            None => (None, after_tag),
+        };
+        if let Some(msg) = after_tag.strip_prefix(':') {
+            message.app_name = Some(rest[..tag_end].to_string());
+            message.procid = procid;
+            rest = msg.strip_prefix(' ').unwrap_or(msg);
+        }
+    }
+    message.message = rest.to_string();
+    message
+}
+
+/// The `<length> ` prefix of an octet-counted frame, as the prefix size and the message length.
+fn octet_count(buf: &[u8]) -> Option<(usize, usize)> {
+    let digits = buf.iter().take_while(|b| b.is_ascii_digit()).count();
+    if !(1..=10).contains(&digits) || buf.get(digits) != Some(&b' ') {
+        return None;
+    }
+    let length = std::str::from_utf8(&buf[..digits]).ok()?.parse::<usize>().ok()?;
+    Some((digits + 1, length))
+}
+
+/// Cuts the next message off a TCP stream (RFC 6587): octet counting when it starts
+/// with `<length> `, otherwise terminated by LF (or NUL).
+fn next_frame(buf: &mut Vec<u8>, max_message_size: usize) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    let start = buf.iter().position(|b| !matches!(b, b'\r' | b'\n' | b'\0')).unwrap_or(buf.len());
+    buf.drain(..start);
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if let Some((prefix, length)) = octet_count(buf) {
+        if length > max_message_size {
+            return Err(anyhow::anyhow!("Message of {} bytes exceeds max_message_size={}", length, max_message_size));
+        }
+        if buf.len() < prefix + length {
+            return Ok(None);
+        }
+        let frame = buf[prefix..prefix + length].to_vec();
+        buf.drain(..prefix + length);
+        return Ok(Some(frame));
+    }
+    match buf.iter().position(|b| matches!(b, b'\n' | b'\0')) {
+        Some(end) => {
+            let frame = buf[..end].to_vec();
+            buf.drain(..=end);
+            Ok(Some(frame))
+        }
+        None if buf.len() > max_message_size => {
+            Err(anyhow::anyhow!("Message exceeds max_message_size={} without a line break", max_message_size))
+        }
+        None => Ok(None),
+    }
+}
+
+async fn forward(grpc_config: &GrpcCfg, raw: &[u8], source_addr: SocketAddr, transport: &'static str) {
+    let message = parse(raw);
+    trace!("Parsed syslog message from {}: {:?}", source_addr, message);
+    let metadata = MetaData {
+        source_addr: source_addr.to_string(),
+        transport,
+    };
+    let meta_json = json!(metadata);
+    if let Err(e) = grpc::send(
+        grpc_config,
+        "syslog",
+        "application/json",
+        &serde_json::to_string(&meta_json).unwrap(),
+        &serde_json::to_vec(&message).unwrap(),
+    )
+    .await
+    {
+        error!("Failed to send to gRPC: {:?}", e);
+    }
+}
+
+pub struct Syslog {
+    config: CollectorCfg,
+}
+
+pub struct SyslogFactory {
+    config: CollectorCfg,
+}
+
+impl SyslogFactory {
+    pub fn new(config: CollectorCfg) -> Self {
+        Self { config }
+    }
+}
+
+impl CollectorFactory for SyslogFactory {
+    fn create(&self) -> Box<dyn Collector> {
+        Box::new(Syslog { config: self.config.clone() })
+    }
+}
+
+impl Collector for Syslog {
+    fn name(&self) -> &'static str {
+        "syslog"
+    }
+
+    fn is_enable(&self) -> bool {
+        self.config.syslog.enable
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn start(&self) -> Result<(), anyhow::Error> {
+        let syslog_config = &self.config.syslog;
+        if syslog_config.udp_port == 0 && syslog_config.tcp_port == 0 {
+            return Err(anyhow::anyhow!("Syslog collector requires KRKNC_SYSLOG_UDP_PORT or KRKNC_SYSLOG_TCP_PORT"));
+        }
+        tokio::try_join!(self.run_udp(syslog_config), self.run_tcp(syslog_config))?;
+        Ok(())
+    }
+}
+
+impl Syslog {
+    async fn run_udp(&self, syslog_config: &SyslogCfg) -> Result<(), anyhow::Error> {
+        if syslog_config.udp_port == 0 {
+            return Ok(());
+        }
+        let addr = format!("{}:{}", syslog_config.host, syslog_config.udp_port);
+        let socket = UdpSocket::bind(&addr).await?;
+        info!("Syslog collector listening on udp://{}", addr);
+
+        let mut buf = vec![0u8; syslog_config.max_message_size];
+        loop {
+            match socket.recv_from(&mut buf).await {
+                Ok((n, source_addr)) => forward(&self.config.grpc, &buf[..n], source_addr, "udp").await,
+                Err(e) => error!("Syslog UDP receive error: {:?}", e),
+            }
+        }
+    }
+
+    async fn run_tcp(&self, syslog_config: &SyslogCfg) -> Result<(), anyhow::Error> {
+        if syslog_config.tcp_port == 0 {
+            return Ok(());
+        }
+        let addr = format!("{}:{}", syslog_config.host, syslog_config.tcp_port);
+        let listener = TcpListener::bind(&addr).await?;
+        info!("Syslog collector listening on tcp://{}", addr);
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer_addr)) => {
+                    debug!("Accepted syslog connection from {}", peer_addr);
+                    let grpc_config = self.config.grpc.clone();
+                    let max_message_size = syslog_config.max_message_size;
+                    tokio::spawn(async move {
+                        handle_connection(stream, peer_addr, grpc_config, max_message_size).await;
+                    });
+                }
+                Err(e) => error!("Failed to accept syslog connection: {:?}", e),
+            }
+        }
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, peer_addr: SocketAddr, grpc_config: GrpcCfg, max_message_size: usize) {
+    let mut buf = Vec::new();
+    let mut read_buf = vec![0u8; 4096];
+    loop {
+        let n = match stream.read(&mut read_buf).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                error!("Syslog read error from {}: {:?}", peer_addr, e);
+                break;
+            }
+        };
+        buf.extend_from_slice(&read_buf[..n]);
+        loop {
+            match next_frame(&mut buf, max_message_size) {
+                Ok(Some(frame)) => forward(&grpc_config, &frame, peer_addr, "tcp").await,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Closing syslog connection from {}: {}", peer_addr, e);
+                    return;
+                }
+            }
+        }
+    }
+    // A last line without a trailing LF is still a complete message; a truncated octet-counted one is not.
+    if !buf.is_empty() && octet_count(&buf).is_none() {
+        forward(&grpc_config, &buf, peer_addr, "tcp").await;
+    }
+    debug!("Syslog connection from {} closed", peer_addr);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rfc3164_with_non_ascii_does_not_panic() {
+        let message = parse("<13>ééabcdefghijk overheated".as_bytes());
+        assert_eq!(message.format, "rfc3164");
+        assert_eq!(message.timestamp, None);
+        assert_eq!(message.message, "ééabcdefghijk overheated");
+    }
+
+    #[test]
+    fn parse_rfc3164_timestamp() {
+        let message = parse(b"<34>Oct 11 22:14:15 mymachine su[42]: 'su root' failed");
+        assert_eq!(message.timestamp.as_deref(), Some("Oct 11 22:14:15"));
+        assert_eq!(message.hostname.as_deref(), Some("mymachine"));
+        assert_eq!(message.app_name.as_deref(), Some("su"));
+        assert_eq!(message.procid.as_deref(), Some("42"));
+        assert_eq!(message.message, "'su root' failed");
+    }
+
+    #[test]
+    fn next_frame_octet_counted() {
+        let mut buf = b"10 <13>hello\n11 <13>hel".to_vec();
+        assert_eq!(next_frame(&mut buf, 1024).unwrap(), Some(b"<13>hello\n".to_vec()));
+        assert_eq!(next_frame(&mut buf, 1024).unwrap(), None);
+        buf.extend_from_slice(b"lo!!");
+        assert_eq!(next_frame(&mut buf, 1024).unwrap(), Some(b"<13>hello!!".to_vec()));
+    }
+
+    #[test]
+    fn next_frame_line_starting_with_digit() {
+        let mut buf = b"2024-01-01T00:00:00Z host app: up\n".to_vec();
+        assert_eq!(next_frame(&mut buf, 1024).unwrap(), Some(b"2024-01-01T00:00:00Z host app: up".to_vec()));
+        assert!(buf.is_empty());
+    }
+}
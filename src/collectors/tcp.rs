@@ -1,14 +1,114 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use serde_json::json;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::Instant;
 use super::Collector;
 use super::CollectorFactory;
 use super::grpc;
-use crate::config::CollectorCfg;
+use super::downlink::{self, DownlinkMessage};
+use super::framing::{self, FrameDecoder};
+use super::tls;
+use crate::config::{CollectorCfg, GrpcCfg, TcpCfg};
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug, serde::Serialize)]
 struct MetaData {
     peer_addr: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_cert_subject: Option<String>,
+}
+
+/// Downlink queues of the connected clients, keyed by peer address (server mode)
+/// or by configured target (client mode).
+type Peers = Arc<Mutex<HashMap<String, mpsc::Sender<DownlinkMessage>>>>;
+
+/// A target connected to in client mode, as written in `KRKNC_TCP_TARGETS`.
+struct Target {
+    name: String,
+    poll: Option<Vec<u8>>,
+}
+
+/// An address block such as `192.168.1.0/24` or `fd00::/8`; a bare address matches only itself.
+#[derive(Debug, Clone)]
+struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    fn parse(value: &str) -> Result<Self, anyhow::Error> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let network: IpAddr = address.parse()
+            .map_err(|_| anyhow::anyhow!("Invalid address in CIDR {:?}", value))?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u32>().ok().filter(|p| *p <= max_prefix)
+                .ok_or_else(|| anyhow::anyhow!("Invalid prefix length in CIDR {:?}", value))?,
+            None => max_prefix,
+        };
+        Ok(Self { network, prefix })
+    }
+
+    fn contains(&self, addr: IpAddr) -> bool {
+        // Clients on a dual-stack socket show up as IPv4-mapped IPv6 addresses.
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            v4 => v4,
+        };
+        match (self.network, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Accept-time checks: deny list first, then the allow list when one is configured.
+struct AccessControl {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    rejected: AtomicU64,
+}
+
+impl AccessControl {
+    fn new(config: &TcpCfg) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            allow: config.allow_cidrs.iter().map(|cidr| Cidr::parse(cidr)).collect::<Result<_, _>>()?,
+            deny: config.deny_cidrs.iter().map(|cidr| Cidr::parse(cidr)).collect::<Result<_, _>>()?,
+            rejected: AtomicU64::new(0),
+        })
+    }
+
+    fn check(&self, addr: IpAddr) -> Result<(), &'static str> {
+        if self.deny.iter().any(|cidr| cidr.contains(addr)) {
+            return Err("denied by KRKNC_TCP_DENY_CIDRS");
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|cidr| cidr.contains(addr)) {
+            return Err("not in KRKNC_TCP_ALLOW_CIDRS");
+        }
+        Ok(())
+    }
+
+    fn reject(&self, peer_addr: SocketAddr, reason: &str) {
+        let total = self.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!("Rejected TCP connection from {}: {} ({} rejected in total)", peer_addr, reason, total);
+    }
 }
 
 pub struct Tcp {
@@ -44,64 +144,85 @@ impl Collector for Tcp {
     async fn start(&self) -> Result<(), anyhow::Error> {
         let addr = format!("{}:{}", self.config.tcp.host, self.config.tcp.port);
         let grpc_config = self.config.grpc.clone();
-        let buffer_size = self.config.tcp.buffer_size;
+        let peers: Peers = Arc::default();
+
+        let mut downlink_rx = downlink::register("tcp");
+        let downlink_peers = peers.clone();
+        tokio::spawn(async move {
+            while let Some(message) = downlink_rx.recv().await {
+                let peer = downlink_peers.lock().unwrap().get(&message.target).cloned();
+                match peer {
+                    Some(peer) => {
+                        if let Err(e) = peer.send(message).await {
+                            e.0.acknowledge(Err("TCP client disconnected".to_string()));
+                        }
+                    }
+                    None => {
+                        let reason = format!("TCP client {} is not connected", message.target);
+                        message.acknowledge(Err(reason));
+                    }
+                }
+            }
+        });
+
+        // Validate the framing settings once instead of failing on every connection.
+        if FrameDecoder::new(&self.config.tcp.framing)?.inter_byte_timeout().is_some() {
+            return Err(anyhow::anyhow!("Timeout framing is only supported by the serial collector"));
+        }
+
+        match self.config.tcp.mode.as_str() {
+            "server" => {}
+            "client" => return self.run_client(peers).await,
+            mode => return Err(anyhow::anyhow!("Unknown KRKNC_TCP_MODE {:?}: expected server or client", mode)),
+        }
+
+        let tcp_config = &self.config.tcp;
+        let access_control = AccessControl::new(tcp_config)?;
+        let connection_limit = Arc::new(Semaphore::new(tcp_config.max_connections));
+        let acceptor = match (&tcp_config.tls_cert_path, &tcp_config.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => Some(tls::acceptor(cert_path, key_path, tcp_config.tls_client_ca_path.as_deref())?),
+            _ => None,
+        };
 
         let listener = TcpListener::bind(&addr).await?;
-        info!("TCP collector listening on {} (buffer_size={})", addr, buffer_size);
+        info!(
+            "TCP collector listening on {} (buffer_size={}, framing={}, tls={}, client_auth={}, max_connections={})",
+            addr, tcp_config.buffer_size, tcp_config.framing.mode, acceptor.is_some(),
+            acceptor.is_some() && tcp_config.tls_client_ca_path.is_some(), tcp_config.max_connections,
+        );
 
         loop {
             match listener.accept().await {
-                Ok((mut stream, peer_addr)) => {
+                Ok((stream, peer_addr)) => {
+                    if let Err(reason) = access_control.check(peer_addr.ip()) {
+                        access_control.reject(peer_addr, reason);
+                        continue;
+                    }
+                    let Ok(permit) = connection_limit.clone().try_acquire_owned() else {
+                        access_control.reject(peer_addr, "too many connections");
+                        continue;
+                    };
                     let grpc_config = grpc_config.clone();
-                    let peer_addr_str = peer_addr.to_string();
-                    info!("TCP client connected: {}", peer_addr_str);
-
+                    let tcp_config = tcp_config.clone();
+                    let peers = peers.clone();
+                    let acceptor = acceptor.clone();
                     tokio::spawn(async move {
-                        let mut buf = vec![0u8; buffer_size];
-                        loop {
-                            match stream.read(&mut buf).await {
-                                Ok(0) => {
-                                    info!("TCP client disconnected: {}", peer_addr_str);
-                                    break;
-                                }
-                                Ok(n) => {
-                                    debug!("Received {} bytes from {}", n, peer_addr_str);
-                                    let metadata = MetaData {
-                                        peer_addr: peer_addr_str.clone(),
-                                    };
-                                    let meta_json = json!(metadata);
-                                    match grpc::send(
-                                        &grpc_config,
-                                        "tcp",
-                                        "application/octet-stream",
-                                        &serde_json::to_string(&meta_json).unwrap(),
-                                        &buf[..n],
-                                    )
-                                    .await
-                                    {
-                                        Ok(response) => {
-                                            debug!("Sent {} bytes from {} to gRPC", n, peer_addr_str);
-                                            let kraken_response = response.into_inner();
-                                            // response_type=tcp のとき、payloadをTCPクライアントに書き戻す
-                                            if !kraken_response.payload.is_empty() {
-                                                if let Ok(response_meta) = serde_json::from_str::<serde_json::Value>(&kraken_response.metadata) {
-                                                    if response_meta.get("response_type").and_then(|v| v.as_str()) == Some("tcp") {
-                                                        match stream.write_all(&kraken_response.payload).await {
-                                                            Ok(_) => debug!("Sent {} bytes response to TCP client {}", kraken_response.payload.len(), peer_addr_str),
-                                                            Err(e) => error!("Failed to write response to TCP client {}: {:?}", peer_addr_str, e),
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        Err(e) => error!("Failed to send to gRPC: {:?}", e),
-                                    }
-                                }
-                                Err(e) => {
-                                    error!("TCP read error from {}: {:?}", peer_addr_str, e);
-                                    break;
-                                }
+                        // Held until the connection ends.
+                        let _permit = permit;
+                        let Some(acceptor) = acceptor else {
+                            handle_connection(stream, peer_addr, None, grpc_config, tcp_config, peers, None).await;
+                            return;
+                        };
+                        // The handshake holds a permit too, so it gets a short limit of its own.
+                        match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                            Ok(Ok(stream)) => {
+                                let subject = stream.get_ref().1.peer_certificates()
+                                    .and_then(|certs| certs.first())
+                                    .and_then(tls::subject_of);
+                                handle_connection(stream, peer_addr, subject, grpc_config, tcp_config, peers, None).await;
                             }
+                            Ok(Err(e)) => warn!("TLS handshake with {} failed: {}", peer_addr, e),
+                            Err(_) => warn!("TLS handshake with {} timed out after {:?}", peer_addr, TLS_HANDSHAKE_TIMEOUT),
                         }
                     });
                 }
@@ -112,3 +233,231 @@ impl Collector for Tcp {
         }
     }
 }
+
+impl Tcp {
+    /// Connects to every target and keeps the connections up, for devices that act as servers.
+    async fn run_client(&self, peers: Peers) -> Result<(), anyhow::Error> {
+        let tcp_config = &self.config.tcp;
+        if tcp_config.targets.is_empty() {
+            return Err(anyhow::anyhow!("TCP client mode requires KRKNC_TCP_TARGETS"));
+        }
+        let poll = tcp_config.poll_string.as_deref().map(framing::unescape).transpose()?;
+        info!(
+            "TCP collector connecting to {} (framing={}, poll_interval_sec={})",
+            tcp_config.targets.join(", "), tcp_config.framing.mode, tcp_config.poll_interval_sec,
+        );
+
+        let mut handles = Vec::new();
+        for target in &tcp_config.targets {
+            handles.push(tokio::spawn(connect_loop(
+                target.clone(),
+                self.config.grpc.clone(),
+                tcp_config.clone(),
+                peers.clone(),
+                poll.clone(),
+            )));
+        }
+        for handle in handles {
+            handle.await?;
+        }
+        Ok(())
+    }
+}
+
+async fn connect_loop(target: String, grpc_config: GrpcCfg, tcp_config: TcpCfg, peers: Peers, poll: Option<Vec<u8>>) {
+    let max_delay = Duration::from_secs(tcp_config.reconnect_max_sec.max(1));
+    let mut retry_delay = Duration::from_secs(1);
+    loop {
+        match TcpStream::connect(&target).await {
+            Ok(stream) => {
+                retry_delay = Duration::from_secs(1);
+                match stream.peer_addr() {
+                    Ok(peer_addr) => {
+                        let client_target = Target { name: target.clone(), poll: poll.clone() };
+                        handle_connection(stream, peer_addr, None, grpc_config.clone(), tcp_config.clone(), peers.clone(), Some(client_target)).await;
+                    }
+                    Err(e) => error!("Failed to get peer address of TCP target {}: {:?}", target, e),
+                }
+            }
+            Err(e) => warn!("Failed to connect to TCP target {}: {}", target, e),
+        }
+        info!("Reconnecting to TCP target {} in {:?}", target, retry_delay);
+        tokio::time::sleep(retry_delay).await;
+        retry_delay = (retry_delay * 2).min(max_delay);
+    }
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    peer_addr: SocketAddr,
+    client_cert_subject: Option<String>,
+    grpc_config: GrpcCfg,
+    tcp_config: TcpCfg,
+    peers: Peers,
+    target: Option<Target>,
+) {
+    let peer_addr_str = peer_addr.to_string();
+    // The idle timeout protects the server; a polled device in client mode may stay quiet between polls.
+    let idle_timeout = match target {
+        Some(_) => Duration::ZERO,
+        None => Duration::from_secs(tcp_config.idle_timeout_sec),
+    };
+    // A target keeps its configured name across reconnects, whatever address it resolves to.
+    let (peer_key, poll) = match target {
+        Some(target) => (target.name, target.poll),
+        None => (peer_addr_str.clone(), None),
+    };
+    match &client_cert_subject {
+        Some(subject) => info!("TCP client connected: {} ({})", peer_addr_str, subject),
+        None => info!("TCP client connected: {}", peer_addr_str),
+    }
+    let metadata = MetaData {
+        peer_addr: peer_addr_str.clone(),
+        client_cert_subject,
+    };
+    let meta_json = serde_json::to_string(&json!(metadata)).unwrap();
+    let (downlink_tx, mut downlink_rx) = mpsc::channel::<DownlinkMessage>(16);
+    peers.lock().unwrap().insert(peer_key.clone(), downlink_tx);
+
+    // The settings were validated when the collector started.
+    let mut decoder = FrameDecoder::new(&tcp_config.framing).unwrap();
+    let mut buf = vec![0u8; tcp_config.buffer_size];
+    let idle = tokio::time::sleep(idle_timeout);
+    tokio::pin!(idle);
+
+    // In client mode the poll string is sent on connect, then every poll interval.
+    let poll_interval = Duration::from_secs(tcp_config.poll_interval_sec);
+    if let Some(poll) = &poll {
+        if let Err(e) = stream.write_all(poll).await {
+            error!("Failed to send poll to {}: {:?}", peer_addr_str, e);
+        }
+    }
+    let poll_period = poll_interval.max(Duration::from_secs(1));
+    let mut poll_timer = tokio::time::interval_at(Instant::now() + poll_period, poll_period);
+
+    'connection: loop {
+        tokio::select! {
+            _ = poll_timer.tick(), if poll.is_some() && !poll_interval.is_zero() => {
+                let poll = poll.as_deref().unwrap_or_default();
+                if let Err(e) = stream.write_all(poll).await {
+                    error!("Failed to send poll to {}: {:?}", peer_addr_str, e);
+                    break;
+                }
+                trace!("Sent {} bytes poll to {}", poll.len(), peer_addr_str);
+            }
+            _ = &mut idle, if !idle_timeout.is_zero() => {
+                info!("Closing idle TCP connection with {} (no data for {:?})", peer_addr_str, idle_timeout);
+                break;
+            }
+            read = stream.read(&mut buf) => match read {
+                Ok(0) => {
+                    if decoder.pending() > 0 {
+                        debug!("Discarded {} bytes of incomplete frame from {}", decoder.pending(), peer_addr_str);
+                    }
+                    info!("TCP client disconnected: {}", peer_addr_str);
+                    break;
+                }
+                Ok(n) => {
+                    debug!("Received {} bytes from {}", n, peer_addr_str);
+                    idle.as_mut().reset(Instant::now() + idle_timeout);
+                    decoder.extend(&buf[..n]);
+                    loop {
+                        match decoder.next_frame() {
+                            Ok(Some(frame)) => forward_frame(&mut stream, &frame, &grpc_config, &meta_json, &peer_addr_str).await,
+                            Ok(None) => break,
+                            Err(e) => {
+                                error!("Closing TCP connection with {}: {}", peer_addr_str, e);
+                                break 'connection;
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("TCP read error from {}: {:?}", peer_addr_str, e);
+                    break;
+                }
+            },
+            Some(message) = downlink_rx.recv() => {
+                let result = stream.write_all(&message.payload).await.map_err(|e| e.to_string());
+                match &result {
+                    Ok(_) => debug!("Sent {} bytes downlink to TCP client {}", message.payload.len(), peer_addr_str),
+                    Err(e) => error!("Failed to write downlink to TCP client {}: {}", peer_addr_str, e),
+                }
+                message.acknowledge(result);
+            }
+        }
+    }
+    peers.lock().unwrap().remove(&peer_key);
+}
+
+async fn forward_frame<S: AsyncWrite + Unpin>(stream: &mut S, frame: &[u8], grpc_config: &GrpcCfg, meta_json: &str, peer_addr_str: &str) {
+    match grpc::send(
+        grpc_config,
+        "tcp",
+        "application/octet-stream",
+        meta_json,
+        frame,
+    )
+    .await
+    {
+        Ok(response) => {
+            debug!("Sent {} bytes from {} to gRPC", frame.len(), peer_addr_str);
+            let kraken_response = response.into_inner();
+            // response_type=tcp のとき、payloadをTCPクライアントに書き戻す
+            if !kraken_response.payload.is_empty() {
+                if let Ok(response_meta) = serde_json::from_str::<serde_json::Value>(&kraken_response.metadata) {
+                    if response_meta.get("response_type").and_then(|v| v.as_str()) == Some("tcp") {
+                        match stream.write_all(&kraken_response.payload).await {
+                            Ok(_) => debug!("Sent {} bytes response to TCP client {}", kraken_response.payload.len(), peer_addr_str),
+                            Err(e) => error!("Failed to write response to TCP client {}: {:?}", peer_addr_str, e),
+                        }
+                    }
+                }
+            }
+        }
+        Err(e) => error!("Failed to send to gRPC: {:?}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_parse() {
+        assert_eq!(Cidr::parse("192.168.1.0/24").unwrap().prefix, 24);
+        assert_eq!(Cidr::parse("10.0.0.1").unwrap().prefix, 32);
+        assert_eq!(Cidr::parse("fd00::/8").unwrap().prefix, 8);
+        assert!(Cidr::parse("192.168.1.0/33").is_err());
+        assert!(Cidr::parse("192.168.1/24").is_err());
+        assert!(Cidr::parse("10.0.0.0/x").is_err());
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let lan = Cidr::parse("192.168.1.0/24").unwrap();
+        assert!(lan.contains(ip("192.168.1.200")));
+        assert!(!lan.contains(ip("192.168.2.1")));
+        assert!(lan.contains(ip("::ffff:192.168.1.5")));
+        assert!(!lan.contains(ip("fd00::1")));
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+        assert!(Cidr::parse("fd00::/8").unwrap().contains(ip("fd12::1")));
+        assert!(!Cidr::parse("10.0.0.1").unwrap().contains(ip("10.0.0.2")));
+    }
+
+    #[test]
+    fn access_control_deny_wins() {
+        let access_control = AccessControl {
+            allow: vec![Cidr::parse("10.0.0.0/8").unwrap()],
+            deny: vec![Cidr::parse("10.0.0.13").unwrap()],
+            rejected: AtomicU64::new(0),
+        };
+        assert!(access_control.check(ip("10.1.2.3")).is_ok());
+        assert!(access_control.check(ip("10.0.0.13")).is_err());
+        assert!(access_control.check(ip("192.168.1.1")).is_err());
+    }
+}
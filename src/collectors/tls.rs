@@ -0,0 +1,42 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, anyhow::Error> {
+    Ok(rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, anyhow::Error> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in {}", path))
+}
+
+/// Builds a TLS acceptor from a PEM certificate chain and private key.
+/// With `client_ca_path`, clients must present a certificate signed by that CA.
+pub fn acceptor(cert_path: &str, key_path: &str, client_ca_path: Option<&str>) -> Result<TlsAcceptor, anyhow::Error> {
+    let builder = match client_ca_path {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_path)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(cert_path)?, load_key(key_path)?)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Returns the subject of a DER certificate, e.g. "CN=sensor-01, O=Example".
+pub fn subject_of(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(cert.as_ref()).ok()?;
+    Some(cert.subject().to_string())
+}
@@ -0,0 +1,105 @@
+use std::net::{IpAddr, Ipv4Addr};
+use serde_json::json;
+use tokio::net::UdpSocket;
+use super::Collector;
+use super::CollectorFactory;
+use super::grpc;
+use crate::config::CollectorCfg;
+
+#[derive(Debug, serde::Serialize)]
+struct MetaData {
+    source_addr: String,
+    multicast_group: Option<String>,
+}
+
+pub struct Udp {
+    config: CollectorCfg,
+}
+
+pub struct UdpFactory {
+    config: CollectorCfg,
+}
+
+impl UdpFactory {
+    pub fn new(config: CollectorCfg) -> Self {
+        Self { config }
+    }
+}
+
+impl CollectorFactory for UdpFactory {
+    fn create(&self) -> Box<dyn Collector> {
+        Box::new(Udp { config: self.config.clone() })
+    }
+}
+
+impl Collector for Udp {
+    fn name(&self) -> &'static str {
+        "udp"
+    }
+
+    fn is_enable(&self) -> bool {
+        self.config.udp.enable
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn start(&self) -> Result<(), anyhow::Error> {
+        let udp_config = &self.config.udp;
+        let addr = format!("{}:{}", udp_config.host, udp_config.port);
+        let socket = UdpSocket::bind(&addr).await?;
+
+        if let Some(group) = &udp_config.multicast_group {
+            match group.parse::<IpAddr>()? {
+                IpAddr::V4(group) => {
+                    let interface: Ipv4Addr = udp_config.multicast_interface.parse()?;
+                    socket.join_multicast_v4(group, interface)?;
+                }
+                // Interface index 0 lets the OS choose.
+                IpAddr::V6(group) => socket.join_multicast_v6(&group, 0)?,
+            }
+            info!("UDP collector joined multicast group {}", group);
+        }
+        info!("UDP collector listening on {} (buffer_size={})", addr, udp_config.buffer_size);
+
+        let mut buf = vec![0u8; udp_config.buffer_size];
+        loop {
+            let (n, source_addr) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("UDP receive error: {:?}", e);
+                    continue;
+                }
+            };
+            debug!("Received {} bytes from {}", n, source_addr);
+            let metadata = MetaData {
+                source_addr: source_addr.to_string(),
+                multicast_group: udp_config.multicast_group.clone(),
+            };
+            let meta_json = json!(metadata);
+            match grpc::send(
+                &self.config.grpc,
+                "udp",
+                "application/octet-stream",
+                &serde_json::to_string(&meta_json).unwrap(),
+                &buf[..n],
+            )
+            .await
+            {
+                Ok(response) => {
+                    let kraken_response = response.into_inner();
+                    // response_type=udp のとき、payloadを送信元に返信する
+                    if !kraken_response.payload.is_empty() {
+                        if let Ok(response_meta) = serde_json::from_str::<serde_json::Value>(&kraken_response.metadata) {
+                            if response_meta.get("response_type").and_then(|v| v.as_str()) == Some("udp") {
+                                match socket.send_to(&kraken_response.payload, source_addr).await {
+                                    Ok(sent) => debug!("Sent {} bytes response to {}", sent, source_addr),
+                                    Err(e) => error!("Failed to send response to {}: {:?}", source_addr, e),
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(e) => error!("Failed to send to gRPC: {:?}", e),
+            }
+        }
+    }
+}
@@ -1,42 +1,219 @@
+use std::fs;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use bytes::{Buf, Bytes};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::Deserialize;
+use tokio::sync::{Mutex, Semaphore};
 use tokio::net::TcpListener;
-use http_body_util::{BodyExt, Full};
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{body::Incoming as IncomingBody, header, Method, Request, Response, StatusCode};
 
 #[path = "./support/mod.rs"]
 mod support;
-use support::TokioIo;
+use support::{TokioIo, TokioTimer};
 
-use crate::config::{CollectorCfg, GrpcCfg};
+use crate::config::{CollectorCfg, GrpcCfg, WebhookCfg};
 
 use super::{Collector, CollectorFactory};
 use super::grpc;
+use super::auth::{constant_time_eq, percent_decode};
 
 type BoxBody = http_body_util::combinators::BoxBody<Bytes, hyper::Error>;
 
+#[derive(Debug, serde::Serialize)]
+struct MetaData {
+    client_id: String,
+    auth_method: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiKeyEntry {
+    key: String,
+    client_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct BasicAuthEntry {
+    username: String,
+    password: String,
+    client_id: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AuthFile {
+    #[serde(default)]
+    api_keys: Vec<ApiKeyEntry>,
+    #[serde(default)]
+    basic_auth: Vec<BasicAuthEntry>,
+}
+
+/// Credentials accepted by the webhook endpoint, loaded from `KRKNC_WEBHOOK_AUTH_FILE_PATH`.
+struct WebhookAuth {
+    api_key_header: String,
+    api_key_query_param: String,
+    credentials: AuthFile,
+}
+
+impl WebhookAuth {
+    fn load(config: &WebhookCfg) -> Result<Option<Self>, anyhow::Error> {
+        let path = match &config.auth_file_path {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let content = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read webhook auth file at {}: {}", path, e))?;
+        let credentials: AuthFile = serde_yaml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("Failed to parse webhook auth file at {}: {}", path, e))?;
+        if credentials.api_keys.is_empty() && credentials.basic_auth.is_empty() {
+            return Err(anyhow::anyhow!("Webhook auth file {} contains no credentials", path));
+        }
+        Ok(Some(Self {
+            api_key_header: config.api_key_header.clone(),
+            api_key_query_param: config.api_key_query_param.clone(),
+            credentials,
+        }))
+    }
+
+    fn authenticate<B>(&self, req: &Request<B>) -> Option<MetaData> {
+        if let Some(key) = self.api_key_from(req) {
+            return self.credentials.api_keys.iter()
+                .find(|entry| constant_time_eq(entry.key.as_bytes(), key.as_bytes()))
+                .map(|entry| MetaData {
+                    client_id: entry.client_id.clone(),
+                    auth_method: "api_key".to_string(),
+                });
+        }
+        let (username, password) = basic_credentials_from(req)?;
+        self.credentials.basic_auth.iter()
+            .find(|entry| entry.username == username
+                && constant_time_eq(entry.password.as_bytes(), password.as_bytes()))
+            .map(|entry| MetaData {
+                client_id: entry.client_id.clone().unwrap_or_else(|| entry.username.clone()),
+                auth_method: "basic".to_string(),
+            })
+    }
+
+    fn api_key_from<B>(&self, req: &Request<B>) -> Option<String> {
+        if let Some(value) = req.headers().get(self.api_key_header.as_str()) {
+            return value.to_str().ok().map(|s| s.to_string());
+        }
+        req.uri().query()?
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, _)| *name == self.api_key_query_param)
+            .and_then(|(_, value)| percent_decode(value))
+    }
+
+    fn has_basic_auth(&self) -> bool {
+        !self.credentials.basic_auth.is_empty()
+    }
+}
+
+fn basic_credentials_from<B>(req: &Request<B>) -> Option<(String, String)> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = BASE64.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Tracks in-flight requests on a keep-alive connection so idle ones can be closed.
+struct ConnectionActivity {
+    in_flight: AtomicUsize,
+    last_active: std::sync::Mutex<Instant>,
+}
+
+impl ConnectionActivity {
+    fn new() -> Self {
+        Self {
+            in_flight: AtomicUsize::new(0),
+            last_active: std::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    fn begin(&self) {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn end(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn is_idle_for(&self, timeout: Duration) -> bool {
+        self.in_flight.load(Ordering::SeqCst) == 0
+            && self.last_active.lock().unwrap().elapsed() >= timeout
+    }
+}
+
 fn full<T: Into<Bytes>>(chunk: T) -> BoxBody {
     Full::new(chunk.into())
         .map_err(|never| match never {})
         .boxed()
 }
 
-async fn post_webhook(req: Request<IncomingBody>, grpc_config: Arc<Mutex<GrpcCfg>>) -> Result<Response<BoxBody>, anyhow::Error> {
-    let whole_body = req.collect().await?.aggregate();
+fn unauthorized(auth: &WebhookAuth) -> Response<BoxBody> {
+    let mut builder = Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(header::CONTENT_TYPE, "application/json");
+    if auth.has_basic_auth() {
+        builder = builder.header(header::WWW_AUTHENTICATE, r#"Basic realm="kraken""#);
+    }
+    builder.body(full(Bytes::from(r#"{"status": "UNAUTHORIZED"}"#))).unwrap()
+}
+
+fn payload_too_large(max_body_size: usize) -> Response<BoxBody> {
+    info!("Rejected webhook request exceeding {} bytes", max_body_size);
+    Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(full(Bytes::from(r#"{"status": "PAYLOAD_TOO_LARGE"}"#)))
+        .unwrap()
+}
+
+fn bad_request(e: anyhow::Error) -> Response<BoxBody> {
+    info!("Rejected webhook request: {}", e);
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(full(Bytes::from(r#"{"status": "BAD_REQUEST"}"#)))
+        .unwrap()
+}
+
+async fn post_webhook(req: Request<IncomingBody>, grpc_config: Arc<Mutex<GrpcCfg>>, metadata: Option<MetaData>, max_body_size: usize) -> Result<Response<BoxBody>, anyhow::Error> {
+    let content_length = req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > max_body_size) {
+        return Ok(payload_too_large(max_body_size));
+    }
+    let whole_body = match Limited::new(req.into_body(), max_body_size).collect().await {
+        Ok(collected) => collected.aggregate(),
+        Err(e) if e.is::<LengthLimitError>() => return Ok(payload_too_large(max_body_size)),
+        Err(e) => return Err(anyhow::anyhow!("Failed to read webhook body: {}", e)),
+    };
     let body: serde_json::Value = serde_json::from_reader(whole_body.reader())?;
     debug!("POST /webhook: {}", &body);
     let json_bytes = serde_json::to_vec(&body)?;
+    let meta_json = match metadata {
+        Some(metadata) => serde_json::to_string(&metadata)?,
+        None => "{}".to_string(),
+    };
 
     let grpc_config = grpc_config.lock().await;
     let sent = grpc::send(
         &*grpc_config,
         "webhook",
         "application/json",
-        "{}",
+        &meta_json,
         &json_bytes
     ).await;
 
@@ -53,10 +230,25 @@ async fn post_webhook(req: Request<IncomingBody>, grpc_config: Arc<Mutex<GrpcCfg
     Ok(response)
 }
 
-async fn handle_request(req: Request<IncomingBody>, grpc_config: Arc<Mutex<GrpcCfg>>) -> Result<Response<BoxBody>, anyhow::Error> {
+async fn handle_request(req: Request<IncomingBody>, grpc_config: Arc<Mutex<GrpcCfg>>, auth: Arc<Option<WebhookAuth>>, max_body_size: usize) -> Result<Response<BoxBody>, anyhow::Error> {
     match (req.method(), req.uri().path()) {
         (&Method::GET, "/") => Ok(Response::new(full("OK"))),
-        (&Method::POST, "/webhook") => Ok(post_webhook(req, grpc_config.clone()).await.unwrap()),
+        (&Method::POST, "/webhook") => {
+            let metadata = match auth.as_ref() {
+                Some(auth) => match auth.authenticate(&req) {
+                    Some(metadata) => Some(metadata),
+                    None => {
+                        info!("Rejected unauthenticated webhook request");
+                        return Ok(unauthorized(auth));
+                    }
+                },
+                None => None,
+            };
+            match post_webhook(req, grpc_config.clone(), metadata, max_body_size).await {
+                Ok(response) => Ok(response),
+                Err(e) => Ok(bad_request(e)),
+            }
+        }
         _ => {
             let response = Response::builder()
                 .status(StatusCode::NOT_FOUND)
@@ -100,22 +292,132 @@ impl Collector for Webhook {
     async fn start(&self) -> Result<(), anyhow::Error> {
         let config = self.config.webhook.clone();
         let grpc_config = Arc::new(Mutex::new(self.config.grpc.clone()));  // Arcでラップ
+        let auth = Arc::new(WebhookAuth::load(&config)?);
+        if auth.is_some() {
+            debug!("Webhook authentication is enabled");
+        }
+        let max_body_size = config.max_body_size;
+        let header_read_timeout = Some(Duration::from_secs(config.header_read_timeout_sec))
+            .filter(|d| !d.is_zero());
+        let idle_timeout = Some(Duration::from_secs(config.idle_timeout_sec))
+            .filter(|d| !d.is_zero());
+        let connection_limit = Arc::new(Semaphore::new(config.max_connections));
         let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
         let listener = TcpListener::bind(&addr).await?;
-        debug!("Webhook server is listening on http://{}", addr);
+        debug!("Webhook server is listening on http://{} (max_body_size={}, max_connections={})", addr, max_body_size, config.max_connections);
 
         loop {
-            let (stream, _) = listener.accept().await?;
+            if connection_limit.available_permits() == 0 {
+                info!("Webhook connection limit ({}) reached, waiting for a free slot", config.max_connections);
+            }
+            let permit = connection_limit.clone().acquire_owned().await?;
+            let (stream, peer_addr) = listener.accept().await?;
             let io = TokioIo::new(stream);
             let grpc_config = grpc_config.clone();  // Arc をクローンして共有参照
+            let auth = auth.clone();
+            let activity = Arc::new(ConnectionActivity::new());
             tokio::task::spawn(async move {
-                let service = service_fn(
-                    move |req| handle_request(req, grpc_config.clone())  // grpc_config をクローンして渡す
-                );
-                if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
-                    error!("Failed to serve connection: {:?}", err);
+                let service_activity = activity.clone();
+                let service = service_fn(move |req| {
+                    let grpc_config = grpc_config.clone();  // grpc_config をクローンして渡す
+                    let auth = auth.clone();
+                    let activity = service_activity.clone();
+                    async move {
+                        activity.begin();
+                        let response = handle_request(req, grpc_config, auth, max_body_size).await;
+                        activity.end();
+                        response
+                    }
+                });
+                let conn = http1::Builder::new()
+                    .timer(TokioTimer::new())
+                    .header_read_timeout(header_read_timeout)
+                    .serve_connection(io, service);
+                tokio::pin!(conn);
+
+                let mut idle_check = tokio::time::interval(Duration::from_secs(1));
+                let mut shutting_down = false;
+                loop {
+                    tokio::select! {
+                        served = conn.as_mut() => {
+                            if let Err(err) = served {
+                                error!("Failed to serve connection: {:?}", err);
+                            }
+                            break;
+                        }
+                        _ = idle_check.tick(), if !shutting_down && idle_timeout.is_some() => {
+                            if activity.is_idle_for(idle_timeout.unwrap()) {
+                                debug!("Closing idle webhook connection from {}", peer_addr);
+                                conn.as_mut().graceful_shutdown();
+                                shutting_down = true;
+                            }
+                        }
+                    }
                 }
+                drop(permit);
             });
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> WebhookAuth {
+        WebhookAuth {
+            api_key_header: "x-api-key".to_string(),
+            api_key_query_param: "api_key".to_string(),
+            credentials: AuthFile {
+                api_keys: vec![ApiKeyEntry { key: "k3y&=+".to_string(), client_id: "sensor-a".to_string() }],
+                basic_auth: vec![BasicAuthEntry { username: "gw".to_string(), password: "pa:ss".to_string(), client_id: None }],
+            },
+        }
+    }
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::builder().method(Method::POST).uri(uri);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn api_key_in_header() {
+        let metadata = auth().authenticate(&request("/webhook", &[("x-api-key", "k3y&=+")])).unwrap();
+        assert_eq!(metadata.client_id, "sensor-a");
+        assert_eq!(metadata.auth_method, "api_key");
+        assert!(auth().authenticate(&request("/webhook", &[("x-api-key", "wrong")])).is_none());
+    }
+
+    #[test]
+    fn api_key_in_query_is_percent_decoded() {
+        let metadata = auth().authenticate(&request("/webhook?x=1&api_key=k3y%26%3D%2B", &[])).unwrap();
+        assert_eq!(metadata.client_id, "sensor-a");
+        assert!(auth().authenticate(&request("/webhook?api_key=k3y%2", &[])).is_none());
+    }
+
+    #[test]
+    fn header_takes_precedence_over_query() {
+        let req = request("/webhook?api_key=k3y%26%3D%2B", &[("x-api-key", "wrong")]);
+        assert!(auth().authenticate(&req).is_none());
+    }
+
+    #[test]
+    fn basic_login() {
+        // "gw:pa:ss": only the first colon separates the username.
+        let metadata = auth().authenticate(&request("/webhook", &[("authorization", "Basic Z3c6cGE6c3M=")])).unwrap();
+        assert_eq!(metadata.client_id, "gw");
+        assert_eq!(metadata.auth_method, "basic");
+        // "gw:nope"
+        assert!(auth().authenticate(&request("/webhook", &[("authorization", "Basic Z3c6bm9wZQ==")])).is_none());
+    }
+
+    #[test]
+    fn malformed_authorization_header() {
+        for value in ["Basic !!!", "Basic Z3c=", "Bearer Z3c6cGE6c3M=", "Z3c6cGE6c3M="] {
+            assert!(auth().authenticate(&request("/webhook", &[("authorization", value)])).is_none(), "{}", value);
+        }
+    }
+}
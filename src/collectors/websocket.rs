@@ -1,13 +1,303 @@
-use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Semaphore};
+use tokio_tungstenite::{accept_hdr_async_with_config, WebSocketStream};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{header, HeaderValue, StatusCode};
+use futures::stream::{SplitSink, SplitStream};
 use futures::{SinkExt, StreamExt};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+use serde_json::json;
 
-use crate::config::CollectorCfg;
+use crate::config::{CollectorCfg, WebsocketCfg};
 
 use super::Collector;
 use super::CollectorFactory;
 use super::grpc;
+use super::tls;
+use super::auth::{constant_time_eq, percent_decode};
+use super::downlink::{self, DownlinkMessage};
+
+/// A connected client: its outbound queue and the channels it subscribed to.
+struct Client {
+    tx: mpsc::Sender<Outbound>,
+    channels: HashSet<String>,
+}
+
+/// Connected clients, keyed by client id (the peer address).
+type Clients = Arc<Mutex<HashMap<String, Client>>>;
+
+const OUTBOUND_QUEUE_SIZE: usize = 64;
+const INBOUND_QUEUE_SIZE: usize = 64;
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(30);
+
+enum Outbound {
+    /// Acknowledged once written to this client.
+    Downlink(DownlinkMessage),
+    /// Fanned out to several clients without acknowledgement.
+    Push(Message),
+}
+
+/// Activity of a connection, shared by its reader and writer for keepalive and idle checks.
+struct Activity {
+    last_active: Mutex<Instant>,
+    awaiting_pong: AtomicBool,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self { last_active: Mutex::new(Instant::now()), awaiting_pong: AtomicBool::new(false) }
+    }
+
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_active.lock().unwrap().elapsed()
+    }
+}
+
+/// Recipients of a downlink command or broker response: `*` (or empty) for all
+/// clients, `channel:<name>` for a channel's subscribers, otherwise a client id.
+enum Target {
+    All,
+    Channel(String),
+    Client(String),
+}
+
+impl Target {
+    fn parse(target: &str) -> Self {
+        match target {
+            "" | "*" => Target::All,
+            _ => match target.strip_prefix("channel:") {
+                Some(channel) => Target::Channel(channel.to_string()),
+                None => Target::Client(target.to_string()),
+            },
+        }
+    }
+
+    /// Broker responses choose their recipients with a `target` field in their metadata.
+    fn from_metadata(metadata: &str) -> Option<Self> {
+        let value: serde_json::Value = serde_json::from_str(metadata).ok()?;
+        value.get("target")?.as_str().map(Target::parse)
+    }
+}
+
+/// Queues a message for every client matching the target and returns how many were reached.
+/// Clients whose queue is full are skipped rather than holding up the others.
+fn push(clients: &Clients, target: &Target, message: &Message) -> usize {
+    let clients = clients.lock().unwrap();
+    let mut delivered = 0;
+    for (client_id, client) in clients.iter() {
+        let matches = match target {
+            Target::All => true,
+            Target::Channel(channel) => client.channels.contains(channel),
+            Target::Client(id) => id == client_id,
+        };
+        if !matches {
+            continue;
+        }
+        match client.tx.try_send(Outbound::Push(message.clone())) {
+            Ok(_) => delivered += 1,
+            Err(e) => warn!("Dropped message for WebSocket client {}: {}", client_id, e),
+        }
+    }
+    delivered
+}
+
+/// `{"kraken": "subscribe", "channel": "..."}` or `{"kraken": "unsubscribe", ...}` sent by a client.
+#[derive(Debug, Deserialize)]
+struct ChannelCommand {
+    kraken: String,
+    channel: String,
+}
+
+impl ChannelCommand {
+    fn parse(text: &str) -> Option<Self> {
+        serde_json::from_str::<Self>(text).ok()
+            .filter(|command| matches!(command.kraken.as_str(), "subscribe" | "unsubscribe"))
+    }
+}
+
+/// Headers carrying credentials are not passed on to the broker.
+const REDACTED_HEADERS: [&str; 2] = ["authorization", "cookie"];
+
+/// Details of the HTTP upgrade request, forwarded with every message of the connection.
+#[derive(Debug, Default, serde::Serialize)]
+struct MetaData {
+    client_id: String,
+    auth_client_id: Option<String>,
+    auth_method: Option<String>,
+    source: String,
+    path: String,
+    query: Option<String>,
+    subprotocol: Option<String>,
+    headers: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenEntry {
+    token: String,
+    client_id: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AuthFile {
+    #[serde(default)]
+    tokens: Vec<TokenEntry>,
+    #[serde(default)]
+    allowed_origins: Vec<String>,
+}
+
+/// Upgrade checks loaded from `KRKNC_WEBSOCKET_AUTH_FILE_PATH`.
+#[derive(Debug, Default)]
+struct WebsocketAuth {
+    query_param: String,
+    rules: AuthFile,
+}
+
+impl WebsocketAuth {
+    fn load(config: &WebsocketCfg) -> Result<Self, anyhow::Error> {
+        let rules = match &config.auth_file_path {
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .map_err(|e| anyhow::anyhow!("Failed to read WebSocket auth file at {}: {}", path, e))?;
+                serde_yaml::from_str(&content)
+                    .map_err(|e| anyhow::anyhow!("Failed to parse WebSocket auth file at {}: {}", path, e))?
+            }
+            None => AuthFile::default(),
+        };
+        Ok(Self { query_param: config.auth_query_param.clone(), rules })
+    }
+
+    /// Checks the Origin allow-list (403) and then the token (401).
+    #[allow(clippy::result_large_err)]
+    fn authorize(&self, request: &Request, metadata: &mut MetaData) -> Result<(), ErrorResponse> {
+        if !self.rules.allowed_origins.is_empty() {
+            let origin = request.headers().get(header::ORIGIN).and_then(|value| value.to_str().ok());
+            if !origin.is_some_and(|origin| self.rules.allowed_origins.iter().any(|allowed| allowed == origin)) {
+                warn!("Rejected WebSocket upgrade from {}: origin {:?} is not allowed", metadata.client_id, origin);
+                return Err(error_response(StatusCode::FORBIDDEN, "Origin not allowed"));
+            }
+        }
+        if self.rules.tokens.is_empty() {
+            return Ok(());
+        }
+        let bearer = request.headers().get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| (token.trim().to_string(), "bearer"));
+        let query = request.uri().query().and_then(|query| query.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, _)| *name == self.query_param)
+            .and_then(|(_, token)| percent_decode(token))
+            .map(|token| (token, "query")));
+        let entry = bearer.or(query).and_then(|(token, method)| self.rules.tokens.iter()
+            .find(|entry| constant_time_eq(entry.token.as_bytes(), token.as_bytes()))
+            .map(|entry| (entry, method)));
+        match entry {
+            Some((entry, method)) => {
+                metadata.auth_client_id = Some(entry.client_id.clone());
+                metadata.auth_method = Some(method.to_string());
+                Ok(())
+            }
+            None => {
+                warn!("Rejected WebSocket upgrade from {}: missing or invalid token", metadata.client_id);
+                let mut response = error_response(StatusCode::UNAUTHORIZED, "Unauthorized");
+                response.headers_mut().insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+                Err(response)
+            }
+        }
+    }
+}
+
+fn error_response(status: StatusCode, reason: &str) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(reason.to_string()));
+    *response.status_mut() = status;
+    response
+}
+
+/// Routes the upgrade request to a source and picks the subprotocol,
+/// rejecting unknown paths and clients that only speak unsupported subprotocols.
+// The error type is fixed by tungstenite's handshake callback.
+#[allow(clippy::result_large_err)]
+fn negotiate(request: &Request, mut response: Response, config: &WebsocketCfg, auth: &WebsocketAuth, metadata: &mut MetaData) -> Result<Response, ErrorResponse> {
+    let path = request.uri().path();
+    metadata.source = if config.routes.is_empty() {
+        "websocket".to_string()
+    } else {
+        match config.routes.get(path) {
+            Some(source) => source.clone(),
+            None => {
+                warn!("Rejected WebSocket upgrade from {} for unknown path {}", metadata.client_id, path);
+                return Err(error_response(StatusCode::NOT_FOUND, "Unknown WebSocket path"));
+            }
+        }
+    };
+    auth.authorize(request, metadata)?;
+    metadata.path = path.to_string();
+    // Keep the access token out of the forwarded query string.
+    metadata.query = request.uri().query()
+        .map(|query| query.split('&')
+            .filter(|pair| pair.split('=').next() != Some(auth.query_param.as_str()))
+            .collect::<Vec<_>>()
+            .join("&"))
+        .filter(|query| !query.is_empty());
+
+    let offered: Vec<&str> = request.headers().get_all(header::SEC_WEBSOCKET_PROTOCOL).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|protocol| protocol.trim())
+        .filter(|protocol| !protocol.is_empty())
+        .collect();
+    if !offered.is_empty() && !config.sub_protocols.is_empty() {
+        let Some(protocol) = offered.into_iter().find(|p| config.sub_protocols.iter().any(|s| s == p)) else {
+            warn!("Rejected WebSocket upgrade from {}: no supported subprotocol offered", metadata.client_id);
+            return Err(error_response(StatusCode::BAD_REQUEST, "Unsupported WebSocket subprotocol"));
+        };
+        // Offered tokens are valid header values already, so this cannot fail.
+        if let Ok(value) = HeaderValue::from_str(protocol) {
+            response.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, value);
+        }
+        metadata.subprotocol = Some(protocol.to_string());
+    }
+
+    for (name, value) in request.headers() {
+        if REDACTED_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        if let Ok(value) = value.to_str() {
+            metadata.headers.entry(name.to_string())
+                .and_modify(|existing| { existing.push_str(", "); existing.push_str(value); })
+                .or_insert_with(|| value.to_string());
+        }
+    }
+    Ok(response)
+}
+
+fn close_message(code: CloseCode, reason: &str) -> Message {
+    Message::Close(Some(CloseFrame { code, reason: reason.to_string().into() }))
+}
+
+fn to_ws_message(content_type: &str, payload: Vec<u8>) -> Message {
+    match content_type {
+        "application/json" | "text/plain" | "text/html" => {
+            Message::Text(String::from_utf8_lossy(&payload).to_string().into())
+        }
+        _ => Message::Binary(payload.into()),
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Websocket {
@@ -43,16 +333,74 @@ impl Collector for Websocket {
     async fn start(&self) -> Result<(), anyhow::Error> {
         let ws_config = self.config.websocket.clone();
         let grpc_config = self.config.grpc.clone();
-        
+        let clients: Clients = Arc::default();
+
+        let mut downlink_rx = downlink::register("websocket");
+        let downlink_clients = clients.clone();
+        tokio::spawn(async move {
+            while let Some(message) = downlink_rx.recv().await {
+                let client_id = match Target::parse(&message.target) {
+                    Target::Client(client_id) => client_id,
+                    target => {
+                        let ws_message = to_ws_message(&message.content_type, message.payload.clone());
+                        let result = match push(&downlink_clients, &target, &ws_message) {
+                            0 => Err(format!("No WebSocket client matches {}", message.target)),
+                            _ => Ok(()),
+                        };
+                        message.acknowledge(result);
+                        continue;
+                    }
+                };
+                let client = downlink_clients.lock().unwrap().get(&client_id).map(|client| client.tx.clone());
+                match client {
+                    Some(client) => {
+                        if let Err(e) = client.send(Outbound::Downlink(message)).await {
+                            if let Outbound::Downlink(message) = e.0 {
+                                message.acknowledge(Err("WebSocket client disconnected".to_string()));
+                            }
+                        }
+                    }
+                    None => {
+                        let reason = format!("WebSocket client {} is not connected", message.target);
+                        message.acknowledge(Err(reason));
+                    }
+                }
+            }
+        });
+
+        let auth = Arc::new(WebsocketAuth::load(&ws_config)?);
+        let acceptor = match (&ws_config.tls_cert_path, &ws_config.tls_key_path) {
+            (Some(cert_path), Some(key_path)) => Some(tls::acceptor(cert_path, key_path, None)?),
+            _ => None,
+        };
+        let connection_limit = Arc::new(Semaphore::new(ws_config.max_connections));
         let listener = TcpListener::bind(&ws_config.host).await?;
-        debug!("WebSocket server started, listening on ws://{}", &ws_config.host);
+        let scheme = if acceptor.is_some() { "wss" } else { "ws" };
+        debug!("WebSocket server started, listening on {}://{} (max_connections={})", scheme, &ws_config.host, ws_config.max_connections);
 
-        while let Ok((stream, addr)) = listener.accept().await {
+        loop {
+            // Wait for a free slot before accepting, so excess clients stay in the backlog.
+            let permit = connection_limit.clone().acquire_owned().await?;
+            let Ok((stream, addr)) = listener.accept().await else { break };
+            let acceptor = acceptor.clone();
+            let auth = auth.clone();
+            let ws_config = ws_config.clone();
             let grpc_config = grpc_config.clone();
+            let clients = clients.clone();
             tokio::spawn(async move {
-                if let Err(e) = handle_connection(stream, addr, grpc_config).await {
+                // The handshake holds a permit too, so it gets a short limit of its own.
+                let result = match acceptor {
+                    Some(acceptor) => match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => handle_connection(stream, addr, ws_config, auth, grpc_config, clients).await,
+                        Ok(Err(e)) => Err(anyhow::anyhow!("TLS handshake failed: {}", e)),
+                        Err(_) => Err(anyhow::anyhow!("TLS handshake timed out after {:?}", TLS_HANDSHAKE_TIMEOUT)),
+                    },
+                    None => handle_connection(stream, addr, ws_config, auth, grpc_config, clients).await,
+                };
+                if let Err(e) = result {
                     error!("Error handling WebSocket connection from {}: {}", addr, e);
                 }
+                drop(permit);
             });
         }
         
@@ -60,115 +408,115 @@ impl Collector for Websocket {
     }
 }
 
-async fn handle_connection(
-    stream: TcpStream,
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    stream: S,
     addr: SocketAddr,
+    ws_config: WebsocketCfg,
+    auth: Arc<WebsocketAuth>,
     grpc_config: crate::config::GrpcCfg,
+    clients: Clients,
 ) -> Result<(), anyhow::Error> {
     debug!("New WebSocket connection from {}", addr);
     
-    let ws_stream = accept_async(stream).await?;
-    debug!("WebSocket connection established with {}", addr);
-    
-    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+    let mut metadata = MetaData { client_id: addr.to_string(), ..Default::default() };
+    let limits = WebSocketConfig::default()
+        .max_message_size(Some(ws_config.max_message_size))
+        .max_frame_size(Some(ws_config.max_frame_size));
+    // The callback signature, and so its error type, is fixed by tungstenite.
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| {
+        negotiate(request, response, &ws_config, &auth, &mut metadata)
+    };
+    // The idle timeout only starts once the connection is upgraded, but the upgrade already holds a permit.
+    let ws_stream = match tokio::time::timeout(UPGRADE_TIMEOUT, accept_hdr_async_with_config(stream, callback, Some(limits))).await {
+        Ok(ws_stream) => ws_stream?,
+        Err(_) => return Err(anyhow::anyhow!("WebSocket upgrade timed out after {:?}", UPGRADE_TIMEOUT)),
+    };
+    debug!("WebSocket connection established with {} on {} ({})", addr, metadata.path, metadata.source);
+    let meta_json = serde_json::to_string(&metadata)?;
+
+    let channels = metadata.query.iter()
+        .flat_map(|query| query.split('&'))
+        .filter_map(|pair| pair.strip_prefix("channels="))
+        .flat_map(|channels| channels.split(','))
+        .filter(|channel| !channel.is_empty())
+        .map(|channel| channel.to_string())
+        .collect();
+    let (outbound_tx, outbound_rx) = mpsc::channel::<Outbound>(OUTBOUND_QUEUE_SIZE);
+    clients.lock().unwrap().insert(metadata.client_id.clone(), Client { tx: outbound_tx.clone(), channels });
     
-    // Send initial hello message
-    let hello_message = Message::Text("{\"kraken\": \"hello\"}".to_string().into());
-    ws_sender.send(hello_message).await?;
+    // Send the greeting unless it is disabled
+    if let Some(greeting) = &ws_config.greeting {
+        outbound_tx.send(Outbound::Push(Message::Text(greeting.clone().into()))).await?;
+    }
+
+    let (ws_sender, ws_receiver) = ws_stream.split();
+    let activity = Arc::new(Activity::new());
+    let mut writer = tokio::spawn(write_loop(ws_sender, outbound_rx, activity.clone(), ws_config, addr));
+    let (inbound_tx, inbound_rx) = mpsc::channel::<Message>(INBOUND_QUEUE_SIZE);
+    let forwarder = tokio::spawn(forward_loop(
+        inbound_rx,
+        grpc_config,
+        meta_json,
+        clients.clone(),
+        metadata.client_id.clone(),
+        outbound_tx.clone(),
+    ));
+
+    // Dropping the reader closes the inbound queue, so the forwarder drains what was received.
+    tokio::select! {
+        result = read_loop(ws_receiver, inbound_tx, outbound_tx.clone(), &activity, addr) => {
+            if let Err(e) = result {
+                error!("Error reading from WebSocket client {}: {}", addr, e);
+            }
+        }
+        _ = &mut writer => {}
+    }
+    let _ = forwarder.await;
+
+    // With the last sender gone the writer flushes its queue and closes the connection.
+    clients.lock().unwrap().remove(&metadata.client_id);
+    drop(outbound_tx);
+    let _ = writer.await;
     
+    debug!("WebSocket connection with {} ended", addr);
+    Ok(())
+}
+
+/// Reads frames, answering control frames at once and queueing data messages for the forwarder.
+async fn read_loop<S: AsyncRead + AsyncWrite + Unpin>(
+    mut ws_receiver: SplitStream<WebSocketStream<S>>,
+    inbound_tx: mpsc::Sender<Message>,
+    outbound_tx: mpsc::Sender<Outbound>,
+    activity: &Activity,
+    addr: SocketAddr,
+) -> Result<(), anyhow::Error> {
     while let Some(msg) = ws_receiver.next().await {
-        match msg? {
-            Message::Text(text) => {
-                debug!("Received text message from {}: {}", addr, text);
-                
-                // Send to gRPC server
-                let sent = grpc::send(
-                    &grpc_config,
-                    "websocket",
-                    "application/json",
-                    "{}",
-                    text.as_bytes()
-                ).await;
-                
-                if let Err(e) = sent {
-                    error!("Failed to send message to gRPC server: {}", e);
-                    continue;
-                }
-                
-                let response = sent.unwrap();
-                let kraken_response = response.into_inner();
-                
-                // Check if response should be sent back to this WebSocket client
-                if kraken_response.collector_name == "websocket" {
-                    let response_message = match kraken_response.content_type.as_str() {
-                        "application/json" | "text/plain" | "text/html" => {
-                            // Send as text message
-                            let text = String::from_utf8_lossy(&kraken_response.payload);
-                            Message::Text(text.to_string().into())
-                        }
-                        _ => {
-                            // Send as binary message for other content types
-                            Message::Binary(kraken_response.payload.into())
-                        }
-                    };
-                    
-                    if let Err(e) = ws_sender.send(response_message).await {
-                        error!("Failed to send response to WebSocket client {}: {}", addr, e);
-                        break;
-                    }
-                }
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(WsError::Capacity(e)) => {
+                warn!("Closing WebSocket connection with {}: {}", addr, e);
+                let _ = outbound_tx.send(Outbound::Push(close_message(CloseCode::Size, "Message too big"))).await;
+                break;
             }
-            Message::Binary(data) => {
-                debug!("Received binary message from {} ({} bytes)", addr, data.len());
-                
-                // Send to gRPC server
-                let sent = grpc::send(
-                    &grpc_config,
-                    "websocket",
-                    "application/octet-stream",
-                    "{}",
-                    &data
-                ).await;
-                
-                if let Err(e) = sent {
-                    error!("Failed to send message to gRPC server: {}", e);
-                    continue;
-                }
-                
-                let response = sent.unwrap();
-                let kraken_response = response.into_inner();
-                
-                // Check if response should be sent back to this WebSocket client
-                if kraken_response.collector_name == "websocket" {
-                    let response_message = match kraken_response.content_type.as_str() {
-                        "application/json" | "text/plain" | "text/html" => {
-                            // Send as text message
-                            let text = String::from_utf8_lossy(&kraken_response.payload);
-                            Message::Text(text.to_string().into())
-                        }
-                        _ => {
-                            // Send as binary message for other content types
-                            Message::Binary(kraken_response.payload.into())
-                        }
-                    };
-                    
-                    if let Err(e) = ws_sender.send(response_message).await {
-                        error!("Failed to send response to WebSocket client {}: {}", addr, e);
-                        break;
-                    }
+            Err(e) => return Err(e.into()),
+        };
+        match msg {
+            Message::Text(_) | Message::Binary(_) => {
+                activity.touch();
+                if inbound_tx.send(msg).await.is_err() {
+                    break;
                 }
             }
             Message::Ping(ping_data) => {
                 debug!("Received ping from {}", addr);
-                let pong_message = Message::Pong(ping_data);
-                if let Err(e) = ws_sender.send(pong_message).await {
-                    error!("Failed to send pong to {}: {}", addr, e);
-                    break;
+                if outbound_tx.try_send(Outbound::Push(Message::Pong(ping_data))).is_err() {
+                    warn!("Dropped pong for WebSocket client {}: outbound queue is full", addr);
                 }
             }
             Message::Pong(_) => {
                 debug!("Received pong from {}", addr);
-                // Pong messages are typically just acknowledged
+                activity.awaiting_pong.store(false, Ordering::Relaxed);
             }
             Message::Close(_) => {
                 debug!("WebSocket connection closed by client {}", addr);
@@ -180,7 +528,135 @@ async fn handle_connection(
             }
         }
     }
-    
-    debug!("WebSocket connection with {} ended", addr);
     Ok(())
 }
+
+/// Forwards a client's messages to the broker one at a time, so responses keep their order.
+async fn forward_loop(
+    mut inbound_rx: mpsc::Receiver<Message>,
+    grpc_config: crate::config::GrpcCfg,
+    meta_json: String,
+    clients: Clients,
+    client_id: String,
+    outbound_tx: mpsc::Sender<Outbound>,
+) {
+    while let Some(message) = inbound_rx.recv().await {
+        let (content_type, payload) = match &message {
+            Message::Text(text) => {
+                debug!("Received text message from {}: {}", client_id, text);
+                if let Some(command) = ChannelCommand::parse(text) {
+                    if let Some(client) = clients.lock().unwrap().get_mut(&client_id) {
+                        if command.kraken == "subscribe" {
+                            client.channels.insert(command.channel.clone());
+                        } else {
+                            client.channels.remove(&command.channel);
+                        }
+                    }
+                    debug!("WebSocket client {} {}d to channel {}", client_id, command.kraken, command.channel);
+                    let reply = json!({"kraken": format!("{}d", command.kraken), "channel": command.channel});
+                    if outbound_tx.send(Outbound::Push(Message::Text(reply.to_string().into()))).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                ("application/json", text.as_bytes())
+            }
+            Message::Binary(data) => {
+                debug!("Received binary message from {} ({} bytes)", client_id, data.len());
+                ("application/octet-stream", &data[..])
+            }
+            _ => continue,
+        };
+
+        // Send to gRPC server
+        let kraken_response = match grpc::send(&grpc_config, "websocket", content_type, &meta_json, payload).await {
+            Ok(response) => response.into_inner(),
+            Err(e) => {
+                error!("Failed to send message to gRPC server: {}", e);
+                continue;
+            }
+        };
+
+        // Check if response should be sent back to this WebSocket client
+        if kraken_response.collector_name != "websocket" {
+            continue;
+        }
+        let response_message = to_ws_message(&kraken_response.content_type, kraken_response.payload);
+
+        // Responses addressed elsewhere go to their targets instead of the sender
+        if let Some(target) = Target::from_metadata(&kraken_response.metadata) {
+            let delivered = push(&clients, &target, &response_message);
+            debug!("Pushed broker response to {} WebSocket client(s)", delivered);
+            continue;
+        }
+        if outbound_tx.send(Outbound::Push(response_message)).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Writes queued messages in order and keeps the connection alive with pings,
+/// closing it on a missing pong or when idle.
+async fn write_loop<S: AsyncRead + AsyncWrite + Unpin>(
+    mut ws_sender: SplitSink<WebSocketStream<S>, Message>,
+    mut outbound_rx: mpsc::Receiver<Outbound>,
+    activity: Arc<Activity>,
+    ws_config: WebsocketCfg,
+    addr: SocketAddr,
+) {
+    let ping_interval = Duration::from_secs(ws_config.ping_interval_sec);
+    let pong_timeout = Duration::from_secs(ws_config.pong_timeout_sec);
+    let idle_timeout = Duration::from_secs(ws_config.idle_timeout_sec);
+    let mut last_ping = Instant::now();
+    let mut housekeeping = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        tokio::select! {
+            outbound = outbound_rx.recv() => {
+                let Some(outbound) = outbound else { break };
+                match outbound {
+                    Outbound::Downlink(message) => {
+                        activity.touch();
+                        let result = ws_sender.send(to_ws_message(&message.content_type, message.payload.clone()))
+                            .await
+                            .map_err(|e| e.to_string());
+                        if let Err(e) = &result {
+                            error!("Failed to send downlink to WebSocket client {}: {}", addr, e);
+                        }
+                        message.acknowledge(result);
+                    }
+                    Outbound::Push(message) => {
+                        if matches!(message, Message::Text(_) | Message::Binary(_)) {
+                            activity.touch();
+                        }
+                        if let Err(e) = ws_sender.send(message).await {
+                            error!("Failed to send message to WebSocket client {}: {}", addr, e);
+                            break;
+                        }
+                    }
+                }
+            }
+            _ = housekeeping.tick() => {
+                if activity.awaiting_pong.load(Ordering::Relaxed) && last_ping.elapsed() >= pong_timeout {
+                    warn!("Closing WebSocket connection with {}: no pong within {:?}", addr, pong_timeout);
+                    let _ = ws_sender.send(close_message(CloseCode::Away, "Pong timeout")).await;
+                    break;
+                }
+                if !idle_timeout.is_zero() && activity.idle_for() >= idle_timeout {
+                    debug!("Closing idle WebSocket connection with {}", addr);
+                    let _ = ws_sender.send(close_message(CloseCode::Away, "Idle timeout")).await;
+                    break;
+                }
+                if !ping_interval.is_zero() && !activity.awaiting_pong.load(Ordering::Relaxed) && last_ping.elapsed() >= ping_interval {
+                    if let Err(e) = ws_sender.send(Message::Ping(Default::default())).await {
+                        error!("Failed to send ping to WebSocket client {}: {}", addr, e);
+                        break;
+                    }
+                    last_ping = Instant::now();
+                    activity.awaiting_pong.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+    let _ = ws_sender.close().await;
+}
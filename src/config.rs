@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::env;
 
 #[derive (Clone, Debug)]
@@ -6,20 +7,60 @@ pub struct WebhookCfg {
     #[allow(dead_code)]
     pub path: String,
     pub port: u16,
+    pub auth_file_path: Option<String>,
+    pub api_key_header: String,
+    pub api_key_query_param: String,
+    pub max_body_size: usize,
+    pub header_read_timeout_sec: u64,
+    pub idle_timeout_sec: u64,
+    pub max_connections: usize,
 }
 
 #[derive (Clone, Debug)]
 pub struct MqttCfg {
     pub enable: bool,
     //pub host: String,
-    pub topic: String,
+    pub topics: Vec<String>,
     pub config_path: String,
+    pub mode: String,
+    pub reply_topic_pattern: String,
+    pub acl_file_path: Option<String>,
+    pub stats_interval_sec: u64,
+    pub client: MqttClientCfg,
+}
+
+#[derive (Clone, Debug)]
+pub struct MqttClientCfg {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub clean_session: bool,
+    pub qos: u8,
+    pub keep_alive_sec: u64,
+    pub tls_ca_path: Option<String>,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
 }
 
 #[derive (Clone, Debug)]
 pub struct WebsocketCfg {
     pub enable: bool,
     pub host: String,
+    pub sub_protocols: Vec<String>,
+    pub routes: HashMap<String, String>,
+    pub greeting: Option<String>,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub auth_file_path: Option<String>,
+    pub auth_query_param: String,
+    pub ping_interval_sec: u64,
+    pub pong_timeout_sec: u64,
+    pub idle_timeout_sec: u64,
+    pub max_message_size: usize,
+    pub max_frame_size: usize,
+    pub max_connections: usize,
 }
 
 #[derive (Clone, Debug)]
@@ -35,7 +76,19 @@ pub struct SerialCfg {
     pub device_name: String,
     pub port: String,
     pub baudrate: u32,
-    pub timeout: u64,
+    pub timeout_sec: u64,
+    pub data_bits: u8,
+    pub parity: String,
+    pub stop_bits: u8,
+    pub flow_control: String,
+    pub usb_vid: Option<String>,
+    pub usb_pid: Option<String>,
+    pub usb_serial_number: Option<String>,
+    pub reconnect_max_sec: u64,
+    pub framing: FramingCfg,
+    pub poll_file_path: Option<String>,
+    pub nmea: bool,
+    pub nmea_throttle_sec: u64,
 }
 
 #[derive (Clone, Debug)]
@@ -97,12 +150,95 @@ pub struct BjigCfg {
     pub action_cooldown_sec: u64,
 }
 
+#[derive (Clone, Debug)]
+pub struct FramingCfg {
+    pub mode: String,
+    pub delimiter: String,
+    pub length_prefix: String,
+    pub length_endian: String,
+    pub frame_size: usize,
+    pub max_frame_size: usize,
+    pub oversized_policy: String,
+    pub inter_byte_timeout_ms: u64,
+}
+
+impl FramingCfg {
+    /// Reads `<prefix>_FRAMING`, `<prefix>_DELIMITER` and the other framing variables.
+    pub fn from_env(prefix: &str) -> Self {
+        let var = |name: &str| env::var(format!("{}_{}", prefix, name));
+        Self {
+            mode: var("FRAMING").unwrap_or("raw".to_string()),
+            delimiter: var("DELIMITER").unwrap_or("\\n".to_string()),
+            length_prefix: var("LENGTH_PREFIX").unwrap_or("u16".to_string()),
+            length_endian: var("LENGTH_ENDIAN").unwrap_or("big".to_string()),
+            frame_size: var("FRAME_SIZE").unwrap_or("0".to_string()).parse::<usize>().unwrap_or(0),
+            max_frame_size: var("MAX_FRAME_SIZE").unwrap_or("65536".to_string()).parse::<usize>().unwrap_or(65536),
+            oversized_policy: var("OVERSIZED_FRAME_POLICY").unwrap_or("drop".to_string()),
+            inter_byte_timeout_ms: var("INTER_BYTE_TIMEOUT_MS").unwrap_or("50".to_string()).parse::<u64>().unwrap_or(50),
+        }
+    }
+}
+
 #[derive (Clone, Debug)]
 pub struct TcpCfg {
     pub enable: bool,
     pub host: String,
     pub port: u16,
     pub buffer_size: usize,
+    pub framing: FramingCfg,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub tls_client_ca_path: Option<String>,
+    pub max_connections: usize,
+    pub idle_timeout_sec: u64,
+    pub allow_cidrs: Vec<String>,
+    pub deny_cidrs: Vec<String>,
+    pub mode: String,
+    pub targets: Vec<String>,
+    pub poll_string: Option<String>,
+    pub poll_interval_sec: u64,
+    pub reconnect_max_sec: u64,
+}
+
+#[derive (Clone, Debug)]
+pub struct UdpCfg {
+    pub enable: bool,
+    pub host: String,
+    pub port: u16,
+    pub buffer_size: usize,
+    pub multicast_group: Option<String>,
+    pub multicast_interface: String,
+}
+
+#[derive (Clone, Debug)]
+pub struct ModbusCfg {
+    pub enable: bool,
+    pub map_path: String,
+    pub mode: String,
+    pub host: String,
+    pub serial_port: String,
+    pub baudrate: u32,
+    pub data_bits: u8,
+    pub parity: String,
+    pub stop_bits: u8,
+    pub interval_sec: u64,
+    pub timeout_ms: u64,
+}
+
+#[derive (Clone, Debug)]
+pub struct SyslogCfg {
+    pub enable: bool,
+    pub host: String,
+    pub udp_port: u16,
+    pub tcp_port: u16,
+    pub max_message_size: usize,
+}
+
+#[derive (Clone, Debug)]
+pub struct DownlinkCfg {
+    pub enable: bool,
+    pub host: String,
+    pub ack_timeout_sec: u64,
 }
 
 impl Default for EmailCfg {
@@ -181,6 +317,10 @@ pub struct CollectorCfg {
     #[allow(dead_code)]
     pub bjig: BjigCfg,
     pub tcp: TcpCfg,
+    pub udp: UdpCfg,
+    pub syslog: SyslogCfg,
+    pub modbus: ModbusCfg,
+    pub downlink: DownlinkCfg,
 }
 
 impl Default for CollectorCfg {
@@ -194,10 +334,14 @@ impl Default for CollectorCfg {
         let mut camera_enable = false;
         let mut bjig_enable = false;
         let mut tcp_enable = false;
+        let mut udp_enable = false;
+        let mut syslog_enable = false;
+        let mut modbus_enable = false;
+        let mut downlink_enable = false;
         if env::var("KRKNC_WEBHOOK_PATH").is_ok() {
             webhook_enable = true;
         }
-        if env::var("KRKNC_MQTT_CONFIG_PATH").is_ok() {
+        if env::var("KRKNC_MQTT_CONFIG_PATH").is_ok() || env::var("KRKNC_MQTT_CLIENT_HOST").is_ok() {
             mqtt_enable = true;
         }
         if env::var("KRKNC_WEBSOCKET_HOST").is_ok() {
@@ -218,9 +362,21 @@ impl Default for CollectorCfg {
         if env::var("KRKNC_BJIG_DEVICE_PATH").is_ok() {
             bjig_enable = true;
         }
-        if env::var("KRKNC_TCP_HOST").is_ok() {
+        if env::var("KRKNC_TCP_HOST").is_ok() || env::var("KRKNC_TCP_TARGETS").is_ok() {
             tcp_enable = true;
         }
+        if env::var("KRKNC_UDP_HOST").is_ok() {
+            udp_enable = true;
+        }
+        if env::var("KRKNC_SYSLOG_HOST").is_ok() {
+            syslog_enable = true;
+        }
+        if env::var("KRKNC_MODBUS_MAP_PATH").is_ok() {
+            modbus_enable = true;
+        }
+        if env::var("KRKNC_DOWNLINK_HOST").is_ok() {
+            downlink_enable = true;
+        }
         CollectorCfg {
             grpc: GrpcCfg {
                 host: env::var("KRKNC_BROKER_HOST").unwrap_or("http://[::1]:50051".to_string()),
@@ -229,16 +385,71 @@ impl Default for CollectorCfg {
                 enable: webhook_enable,
                 path: env::var("KRKNC_WEBHOOK_PATH").unwrap_or("/webhook".to_string()),
                 port: env::var("KRKNC_WEBHOOK_PORT").unwrap_or("2792".to_string()).parse::<u16>().unwrap(),
+                auth_file_path: env::var("KRKNC_WEBHOOK_AUTH_FILE_PATH").ok().filter(|s| !s.is_empty()),
+                api_key_header: env::var("KRKNC_WEBHOOK_API_KEY_HEADER").unwrap_or("x-api-key".to_string()),
+                api_key_query_param: env::var("KRKNC_WEBHOOK_API_KEY_QUERY_PARAM").unwrap_or("api_key".to_string()),
+                max_body_size: env::var("KRKNC_WEBHOOK_MAX_BODY_SIZE").unwrap_or("1048576".to_string()).parse::<usize>().unwrap_or(1048576), // 1MB
+                header_read_timeout_sec: env::var("KRKNC_WEBHOOK_HEADER_READ_TIMEOUT_SEC").unwrap_or("30".to_string()).parse::<u64>().unwrap_or(30),
+                idle_timeout_sec: env::var("KRKNC_WEBHOOK_IDLE_TIMEOUT_SEC").unwrap_or("60".to_string()).parse::<u64>().unwrap_or(60),
+                max_connections: env::var("KRKNC_WEBHOOK_MAX_CONNECTIONS").unwrap_or("256".to_string()).parse::<usize>().ok().filter(|n| *n >= 1).unwrap_or(256),
             },
             mqtt: MqttCfg {
                 enable: mqtt_enable,
                 //host: env::var("KRKNC_MQTT_HOST").unwrap_or("127.0.0.1:1883".to_string()),
-                topic: env::var("KRKNC_MQTT_TOPIC").unwrap_or("kraken".to_string()),
+                topics: env::var("KRKNC_MQTT_TOPIC")
+                    .unwrap_or("kraken".to_string())
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
                 config_path: env::var("KRKNC_MQTT_CONFIG_PATH").unwrap_or("config/mqttd.conf".to_string()),
+                mode: env::var("KRKNC_MQTT_MODE").unwrap_or(
+                    if env::var("KRKNC_MQTT_CLIENT_HOST").is_ok() { "client" } else { "broker" }.to_string()
+                ),
+                reply_topic_pattern: env::var("KRKNC_MQTT_REPLY_TOPIC_PATTERN").unwrap_or("{topic}/reply".to_string()),
+                acl_file_path: env::var("KRKNC_MQTT_ACL_FILE_PATH").ok().filter(|s| !s.is_empty()),
+                stats_interval_sec: env::var("KRKNC_MQTT_STATS_INTERVAL_SEC").unwrap_or("60".to_string()).parse::<u64>().unwrap_or(60),
+                client: MqttClientCfg {
+                    host: env::var("KRKNC_MQTT_CLIENT_HOST").unwrap_or("127.0.0.1".to_string()),
+                    port: env::var("KRKNC_MQTT_CLIENT_PORT").unwrap_or("1883".to_string()).parse::<u16>().unwrap_or(1883),
+                    client_id: env::var("KRKNC_MQTT_CLIENT_ID").unwrap_or("kraken-collector".to_string()),
+                    username: env::var("KRKNC_MQTT_CLIENT_USERNAME").ok().filter(|s| !s.is_empty()),
+                    password: env::var("KRKNC_MQTT_CLIENT_PASSWORD").ok().filter(|s| !s.is_empty()),
+                    clean_session: env::var("KRKNC_MQTT_CLIENT_CLEAN_SESSION").unwrap_or("false".to_string()).parse::<bool>().unwrap_or(false),
+                    qos: env::var("KRKNC_MQTT_CLIENT_QOS").unwrap_or("1".to_string()).parse::<u8>().unwrap_or(1),
+                    keep_alive_sec: env::var("KRKNC_MQTT_CLIENT_KEEP_ALIVE_SEC").unwrap_or("30".to_string()).parse::<u64>().unwrap_or(30),
+                    tls_ca_path: env::var("KRKNC_MQTT_CLIENT_TLS_CA_PATH").ok().filter(|s| !s.is_empty()),
+                    tls_cert_path: env::var("KRKNC_MQTT_CLIENT_TLS_CERT_PATH").ok().filter(|s| !s.is_empty()),
+                    tls_key_path: env::var("KRKNC_MQTT_CLIENT_TLS_KEY_PATH").ok().filter(|s| !s.is_empty()),
+                },
             },
             websocket: WebsocketCfg {
                 enable: websocket_enable,
                 host: env::var("KRKNC_WEBSOCKET_HOST").unwrap_or("127.0.0.1:2794".to_string()),
+                sub_protocols: env::var("KRKNC_WEBSOCKET_SUB_PROTOCOL")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+                routes: env::var("KRKNC_WEBSOCKET_ROUTES")
+                    .unwrap_or_default()
+                    .split(',')
+                    .filter_map(|route| route.split_once('='))
+                    .map(|(path, source)| (path.trim().to_string(), source.trim().to_string()))
+                    .collect(),
+                greeting: Some(env::var("KRKNC_WEBSOCKET_GREETING").unwrap_or("{\"kraken\": \"hello\"}".to_string()))
+                    .filter(|s| !s.is_empty()),
+                tls_cert_path: env::var("KRKNC_WEBSOCKET_TLS_CERT_PATH").ok().filter(|s| !s.is_empty()),
+                tls_key_path: env::var("KRKNC_WEBSOCKET_TLS_KEY_PATH").ok().filter(|s| !s.is_empty()),
+                auth_file_path: env::var("KRKNC_WEBSOCKET_AUTH_FILE_PATH").ok().filter(|s| !s.is_empty()),
+                auth_query_param: env::var("KRKNC_WEBSOCKET_AUTH_QUERY_PARAM").unwrap_or("token".to_string()),
+                ping_interval_sec: env::var("KRKNC_WEBSOCKET_PING_INTERVAL_SEC").unwrap_or("30".to_string()).parse::<u64>().unwrap_or(30),
+                pong_timeout_sec: env::var("KRKNC_WEBSOCKET_PONG_TIMEOUT_SEC").unwrap_or("10".to_string()).parse::<u64>().unwrap_or(10),
+                idle_timeout_sec: env::var("KRKNC_WEBSOCKET_IDLE_TIMEOUT_SEC").unwrap_or("300".to_string()).parse::<u64>().unwrap_or(300),
+                max_message_size: env::var("KRKNC_WEBSOCKET_MAX_MESSAGE_SIZE").unwrap_or("1048576".to_string()).parse::<usize>().unwrap_or(1048576),
+                max_frame_size: env::var("KRKNC_WEBSOCKET_MAX_FRAME_SIZE").unwrap_or("1048576".to_string()).parse::<usize>().unwrap_or(1048576),
+                max_connections: env::var("KRKNC_WEBSOCKET_MAX_CONNECTIONS").unwrap_or("256".to_string()).parse::<usize>().ok().filter(|n| *n >= 1).unwrap_or(256),
             },
             ibeacon: IbeaconCfg {
                 enable: ibeacon_enable,
@@ -250,7 +461,19 @@ impl Default for CollectorCfg {
                 device_name: env::var("KRKNC_SERIAL_DEVICE_NAME").unwrap_or("unknown".to_string()),
                 port: env::var("KRKNC_SERIAL_PORT").unwrap_or("/dev/ttyACM0".to_string()),
                 baudrate: env::var("KRKNC_SERIAL_BAUDRATE").unwrap_or("9600".to_string()).parse::<u32>().unwrap(),
-                timeout: env::var("KRKNC_SERIAL_TIMEOUT_SEC").unwrap_or("10".to_string()).parse::<u64>().unwrap(),
+                timeout_sec: env::var("KRKNC_SERIAL_TIMEOUT_SEC").unwrap_or("1".to_string()).parse::<u64>().unwrap(),
+                data_bits: env::var("KRKNC_SERIAL_DATA_BITS").unwrap_or("8".to_string()).parse::<u8>().unwrap_or(8),
+                parity: env::var("KRKNC_SERIAL_PARITY").unwrap_or("none".to_string()),
+                stop_bits: env::var("KRKNC_SERIAL_STOP_BITS").unwrap_or("1".to_string()).parse::<u8>().unwrap_or(1),
+                flow_control: env::var("KRKNC_SERIAL_FLOW_CONTROL").unwrap_or("none".to_string()),
+                usb_vid: env::var("KRKNC_SERIAL_USB_VID").ok().filter(|s| !s.is_empty()),
+                usb_pid: env::var("KRKNC_SERIAL_USB_PID").ok().filter(|s| !s.is_empty()),
+                usb_serial_number: env::var("KRKNC_SERIAL_USB_SERIAL_NUMBER").ok().filter(|s| !s.is_empty()),
+                reconnect_max_sec: env::var("KRKNC_SERIAL_RECONNECT_MAX_SEC").unwrap_or("60".to_string()).parse::<u64>().unwrap_or(60),
+                framing: FramingCfg::from_env("KRKNC_SERIAL"),
+                poll_file_path: env::var("KRKNC_SERIAL_POLL_FILE_PATH").ok().filter(|s| !s.is_empty()),
+                nmea: env::var("KRKNC_SERIAL_NMEA").unwrap_or("false".to_string()).parse::<bool>().unwrap_or(false),
+                nmea_throttle_sec: env::var("KRKNC_SERIAL_NMEA_THROTTLE_SEC").unwrap_or("0".to_string()).parse::<u64>().unwrap_or(0),
             },
             text_file: TextFileCfg {
                 enable: textfile_enable,
@@ -282,6 +505,69 @@ impl Default for CollectorCfg {
                 host: env::var("KRKNC_TCP_HOST").unwrap_or("0.0.0.0".to_string()),
                 port: env::var("KRKNC_TCP_PORT").unwrap_or("9000".to_string()).parse::<u16>().unwrap_or(9000),
                 buffer_size: env::var("KRKNC_TCP_BUFFER_SIZE").unwrap_or("4096".to_string()).parse::<usize>().unwrap_or(4096),
+                framing: FramingCfg::from_env("KRKNC_TCP"),
+                tls_cert_path: env::var("KRKNC_TCP_TLS_CERT_PATH").ok().filter(|s| !s.is_empty()),
+                tls_key_path: env::var("KRKNC_TCP_TLS_KEY_PATH").ok().filter(|s| !s.is_empty()),
+                tls_client_ca_path: env::var("KRKNC_TCP_TLS_CLIENT_CA_PATH").ok().filter(|s| !s.is_empty()),
+                max_connections: env::var("KRKNC_TCP_MAX_CONNECTIONS").unwrap_or("256".to_string()).parse::<usize>().ok().filter(|n| *n >= 1).unwrap_or(256),
+                idle_timeout_sec: env::var("KRKNC_TCP_IDLE_TIMEOUT_SEC").unwrap_or("300".to_string()).parse::<u64>().unwrap_or(300),
+                allow_cidrs: env::var("KRKNC_TCP_ALLOW_CIDRS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+                deny_cidrs: env::var("KRKNC_TCP_DENY_CIDRS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+                mode: env::var("KRKNC_TCP_MODE").unwrap_or(
+                    if env::var("KRKNC_TCP_TARGETS").is_ok() { "client" } else { "server" }.to_string()
+                ),
+                targets: env::var("KRKNC_TCP_TARGETS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+                poll_string: env::var("KRKNC_TCP_POLL_STRING").ok().filter(|s| !s.is_empty()),
+                poll_interval_sec: env::var("KRKNC_TCP_POLL_INTERVAL_SEC").unwrap_or("0".to_string()).parse::<u64>().unwrap_or(0),
+                reconnect_max_sec: env::var("KRKNC_TCP_RECONNECT_MAX_SEC").unwrap_or("60".to_string()).parse::<u64>().unwrap_or(60),
+            },
+            udp: UdpCfg {
+                enable: udp_enable,
+                host: env::var("KRKNC_UDP_HOST").unwrap_or("0.0.0.0".to_string()),
+                port: env::var("KRKNC_UDP_PORT").unwrap_or("9001".to_string()).parse::<u16>().unwrap_or(9001),
+                buffer_size: env::var("KRKNC_UDP_BUFFER_SIZE").unwrap_or("65535".to_string()).parse::<usize>().unwrap_or(65535),
+                multicast_group: env::var("KRKNC_UDP_MULTICAST_GROUP").ok().filter(|s| !s.is_empty()),
+                multicast_interface: env::var("KRKNC_UDP_MULTICAST_INTERFACE").unwrap_or("0.0.0.0".to_string()),
+            },
+            syslog: SyslogCfg {
+                enable: syslog_enable,
+                host: env::var("KRKNC_SYSLOG_HOST").unwrap_or("0.0.0.0".to_string()),
+                udp_port: env::var("KRKNC_SYSLOG_UDP_PORT").unwrap_or("514".to_string()).parse::<u16>().unwrap_or(514),
+                tcp_port: env::var("KRKNC_SYSLOG_TCP_PORT").unwrap_or("514".to_string()).parse::<u16>().unwrap_or(514),
+                max_message_size: env::var("KRKNC_SYSLOG_MAX_MESSAGE_SIZE").unwrap_or("65536".to_string()).parse::<usize>().unwrap_or(65536),
+            },
+            modbus: ModbusCfg {
+                enable: modbus_enable,
+                map_path: env::var("KRKNC_MODBUS_MAP_PATH").unwrap_or("config/modbus_map.yml".to_string()),
+                mode: env::var("KRKNC_MODBUS_MODE").unwrap_or("tcp".to_string()),
+                host: env::var("KRKNC_MODBUS_HOST").unwrap_or("127.0.0.1:502".to_string()),
+                serial_port: env::var("KRKNC_MODBUS_SERIAL_PORT").unwrap_or("/dev/ttyUSB0".to_string()),
+                baudrate: env::var("KRKNC_MODBUS_BAUDRATE").unwrap_or("9600".to_string()).parse::<u32>().unwrap_or(9600),
+                data_bits: env::var("KRKNC_MODBUS_DATA_BITS").unwrap_or("8".to_string()).parse::<u8>().unwrap_or(8),
+                parity: env::var("KRKNC_MODBUS_PARITY").unwrap_or("none".to_string()),
+                stop_bits: env::var("KRKNC_MODBUS_STOP_BITS").unwrap_or("1".to_string()).parse::<u8>().unwrap_or(1),
+                interval_sec: env::var("KRKNC_MODBUS_INTERVAL_SEC").unwrap_or("10".to_string()).parse::<u64>().unwrap_or(10),
+                timeout_ms: env::var("KRKNC_MODBUS_TIMEOUT_MS").unwrap_or("1000".to_string()).parse::<u64>().unwrap_or(1000),
+            },
+            downlink: DownlinkCfg {
+                enable: downlink_enable,
+                host: env::var("KRKNC_DOWNLINK_HOST").unwrap_or("0.0.0.0:50052".to_string()),
+                ack_timeout_sec: env::var("KRKNC_DOWNLINK_ACK_TIMEOUT_SEC").unwrap_or("10".to_string()).parse::<u64>().unwrap_or(10),
             },
         }
     }
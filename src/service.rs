@@ -10,6 +10,10 @@ use crate::{
         camera::CameraFactory,
         email::EmailFactory,
         tcp::TcpFactory,
+        udp::UdpFactory,
+        syslog::SyslogFactory,
+        modbus::ModbusFactory,
+        downlink::DownlinkFactory,
     },
     config::CollectorCfg
 };
@@ -31,6 +35,10 @@ pub async fn start(config: &CollectorCfg) -> Result<(), anyhow::Error> {
         #[cfg(feature = "bjig")]
         Box::new(BjigFactory::new(config.clone())),
         Box::new(TcpFactory::new(config.clone())),
+        Box::new(UdpFactory::new(config.clone())),
+        Box::new(SyslogFactory::new(config.clone())),
+        Box::new(ModbusFactory::new(config.clone())),
+        Box::new(DownlinkFactory::new(config.clone())),
     ];
 
     let mut handles = Vec::new();